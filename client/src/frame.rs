@@ -7,9 +7,19 @@ use tokio::sync::{
     watch::{Receiver, Sender, channel},
 };
 use tokio_stream::wrappers::WatchStream;
+use worker::{
+    RenderedImage,
+    api::{
+        render_store::{RenderStore, StoredRender},
+        render_task::RenderTask,
+    },
+};
 
 pub struct Frame {
     render_sum: Mutex<RenderSum>,
+    render_store: Box<dyn RenderStore>,
+    // Held while saving, so every saved render contains the samples of the previous ones.
+    save_lock: Mutex<()>,
     result_sender: Sender<RgbaImage>,
     result_receiver: Receiver<RgbaImage>,
 }
@@ -19,29 +29,108 @@ impl Hash for Frame {
 }
 
 impl Frame {
-    pub async fn new(width: u32, height: u32) -> Self {
+    pub async fn new(width: u32, height: u32, render_store: Box<dyn RenderStore>) -> Self {
         let (result_sender, result_receiver) = channel(RgbaImage::new(width, height));
 
         Self {
-            render_sum: Mutex::from(RenderSum {
-                sum: Rgb32FImage::new(width, height),
-                count: 0,
-            }),
+            render_sum: Mutex::from(RenderSum::new(String::new(), width, height)),
+            render_store,
+            save_lock: Mutex::default(),
             result_sender,
             result_receiver,
         }
     }
 
-    pub async fn add_render(&self, render: Rgb32FImage) {
-        // TODO: Check that width and height match.
+    // Saves the previous task and continues from the last render stored for the new one.
+    // Returns the number of stored renders.
+    pub async fn reset(&self, render_task: &RenderTask) -> usize {
+        let task_md5 = render_task.md5();
+        let resolution = render_task.camera.resolution;
+        let (width, height) = (resolution.x as u32, resolution.y as u32);
+
+        let _save_lock = self.save_lock.lock().await;
+        let mut previous = std::mem::replace(
+            &mut *self.render_sum.lock().await,
+            RenderSum::new(task_md5.clone(), width, height),
+        );
+        if let Some(previous) = previous.take_unsaved() {
+            self.save_render(&previous).await;
+        }
+
+        let render_count = match self.render_store.render_count(&task_md5).await {
+            Ok(render_count) => render_count,
+            Err(err) => {
+                println!("Failed to load stored renders: {:#}", err);
+                0
+            }
+        };
+        let mut render_sum = self.render_sum.lock().await;
+        if let Some(id) = render_count.checked_sub(1) {
+            let stored = self.render_store.load_render(id, &task_md5).await;
+            let stored = stored.and_then(|stored| {
+                let Some(stored) = stored else {
+                    return Ok(None);
+                };
+                let mut stored_sum = RenderSum::new(task_md5.clone(), width, height);
+                stored_sum.add_stored(&stored)?;
+                Ok(Some(stored_sum))
+            });
+            match stored {
+                Ok(Some(stored)) => {
+                    println!("Loaded stored render {}", id);
+                    *render_sum = stored;
+                }
+                Ok(None) => println!("Stored render {} is missing", id),
+                Err(err) => println!("Failed to load stored render {}: {:#}", id, err),
+            }
+        }
+        let total = render_sum.clone();
+        drop(render_sum);
+
+        self.send_result(total);
+        render_count
+    }
 
+    // Renders of tasks other than the current one are ignored.
+    pub async fn add_render(&self, task_md5: &str, render: RenderedImage) {
         let mut render_sum = self.render_sum.lock().await;
-        render_sum.add_render(render);
+        if render_sum.task_md5 != task_md5 {
+            return;
+        }
+        render_sum.add_render(&render);
 
-        let render_sum_clone = render_sum.clone();
+        let total = render_sum.clone();
         drop(render_sum);
 
-        let image = render_sum_clone.into_image();
+        self.send_result(total);
+    }
+
+    // Stores all the samples of the task as a new render, if there are new ones.
+    pub async fn save(&self) {
+        let _save_lock = self.save_lock.lock().await;
+        let unsaved = self.render_sum.lock().await.take_unsaved();
+        if let Some(unsaved) = unsaved {
+            self.save_render(&unsaved).await;
+        }
+    }
+
+    async fn save_render(&self, render_sum: &RenderSum) {
+        let result = self
+            .render_store
+            .save_render(&render_sum.task_md5, &render_sum.to_stored())
+            .await;
+        if let Err(err) = result {
+            println!("Failed to save render: {:#}", err);
+            // Saved again next time, unless the task has changed.
+            let mut current = self.render_sum.lock().await;
+            if current.task_md5 == render_sum.task_md5 {
+                current.unsaved = true;
+            }
+        }
+    }
+
+    fn send_result(&self, render_sum: RenderSum) {
+        let image = render_sum.into_image();
         let image = gamma_correction(image);
         self.result_sender.send(image).unwrap();
     }
@@ -53,30 +142,94 @@ impl Frame {
 
 #[derive(Clone)]
 struct RenderSum {
+    task_md5: String,
+    // Renders weighted by their sample counts.
     sum: Rgb32FImage,
-    count: usize,
+    // Renders can cover only a part of the frame, so samples are counted per pixel.
+    sample_counts: Vec<u32>,
+    // Set when samples are added after the last save.
+    unsaved: bool,
 }
 
 impl RenderSum {
-    fn add_render(&mut self, render: Rgb32FImage) {
-        for x in 0..render.width() {
-            for y in 0..render.height() {
-                let pixel = self.sum.get_pixel_mut(x, y);
-                let rendered_pixel = render.get_pixel(x, y);
-                pixel.0 = [0, 1, 2].map(|i| pixel.0[i] + rendered_pixel.0[i]);
+    fn new(task_md5: String, width: u32, height: u32) -> Self {
+        Self {
+            task_md5,
+            sum: Rgb32FImage::new(width, height),
+            sample_counts: vec![0; (width * height) as usize],
+            unsaved: false,
+        }
+    }
+
+    // Sum to save, if there are samples added since the last save.
+    fn take_unsaved(&mut self) -> Option<RenderSum> {
+        if !self.unsaved {
+            return None;
+        }
+        self.unsaved = false;
+        Some(self.clone())
+    }
+
+    fn add_render(&mut self, render: &RenderedImage) {
+        let (offset_x, offset_y) = render.offset;
+        // Parts of the render outside of the frame are ignored.
+        let width = render
+            .image
+            .width()
+            .min(self.sum.width().saturating_sub(offset_x));
+        let height = render
+            .image
+            .height()
+            .min(self.sum.height().saturating_sub(offset_y));
+
+        for x in 0..width {
+            for y in 0..height {
+                let (frame_x, frame_y) = (x + offset_x, y + offset_y);
+                let pixel = self.sum.get_pixel_mut(frame_x, frame_y);
+                let rendered_pixel = render.image.get_pixel(x, y);
+                let weight = render.sample_count as f32;
+                pixel.0 = [0, 1, 2].map(|i| pixel.0[i] + rendered_pixel.0[i] * weight);
+                self.sample_counts[(frame_x + frame_y * self.sum.width()) as usize] +=
+                    render.sample_count;
             }
         }
+        self.unsaved = true;
+    }
+
+    fn add_stored(&mut self, render: &StoredRender) -> anyhow::Result<()> {
+        if render.image.dimensions() != self.sum.dimensions() {
+            anyhow::bail!("Render size doesn't match the frame");
+        }
+
+        for (pixel, (stored_pixel, &sample_count)) in self
+            .sum
+            .pixels_mut()
+            .zip(render.image.pixels().zip(&render.sample_counts))
+        {
+            let weight = sample_count as f32;
+            pixel.0 = [0, 1, 2].map(|i| pixel.0[i] + stored_pixel.0[i] * weight);
+        }
+        for (count, stored_count) in self.sample_counts.iter_mut().zip(&render.sample_counts) {
+            *count += stored_count;
+        }
+        Ok(())
+    }
 
-        self.count += 1;
+    fn to_stored(&self) -> StoredRender {
+        StoredRender {
+            image: self.clone().into_image(),
+            sample_counts: self.sample_counts.clone(),
+        }
     }
 
     fn into_image(mut self) -> Rgb32FImage {
-        let render_count = self.count.max(1);
-        for x in 0..self.sum.width() {
+        let width = self.sum.width();
+        for x in 0..width {
             for y in 0..self.sum.height() {
+                let sample_count = self.sample_counts[(x + y * width) as usize].max(1);
                 self.sum
                     .get_pixel_mut(x, y)
-                    .apply(|ch| ch / render_count as f32);
+                    .apply(|ch| ch / sample_count as f32);
             }
         }
         self.sum
@@ -101,3 +254,63 @@ fn gamma_correction(image: Rgb32FImage) -> RgbaImage {
 
     gamma_corrected_image
 }
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    fn render(
+        width: u32,
+        height: u32,
+        value: f32,
+        offset: (u32, u32),
+        sample_count: u32,
+    ) -> RenderedImage {
+        RenderedImage {
+            image: Rgb32FImage::from_pixel(width, height, Rgb([value; 3])),
+            offset,
+            sample_count,
+            capped_paths: 0,
+        }
+    }
+
+    fn assert_image(image: &Rgb32FImage, expected: &[f32]) {
+        for (pixel, &expected) in image.pixels().zip(expected) {
+            for channel in pixel.0 {
+                assert!((channel - expected).abs() < 1e-5, "{channel} != {expected}");
+            }
+        }
+    }
+
+    #[test]
+    fn renders_are_weighted_by_sample_counts() {
+        let mut sum = RenderSum::new(String::new(), 3, 2);
+        sum.add_render(&render(2, 2, 1.0, (0, 0), 2));
+        sum.add_render(&render(2, 2, 4.0, (1, 0), 3));
+        // Partly outside of the frame.
+        sum.add_render(&render(2, 1, 0.5, (2, 1), 1));
+
+        assert_eq!(sum.sample_counts, [2, 5, 3, 2, 5, 4]);
+        assert_image(&sum.into_image(), &[1.0, 2.8, 4.0, 1.0, 2.8, 12.5 / 4.0]);
+    }
+
+    #[test]
+    fn stored_render_is_the_same() {
+        let mut sum = RenderSum::new(String::new(), 3, 2);
+        sum.add_render(&render(2, 2, 1.0, (0, 0), 2));
+        sum.add_render(&render(2, 2, 4.0, (1, 0), 3));
+        let stored = sum.to_stored();
+
+        let mut loaded = RenderSum::new(String::new(), 3, 2);
+        loaded.add_stored(&stored).unwrap();
+        loaded.add_render(&render(1, 1, 7.0, (0, 1), 1));
+
+        assert_eq!(loaded.sample_counts, [2, 5, 3, 3, 5, 3]);
+        assert_image(&loaded.into_image(), &[1.0, 2.8, 4.0, 3.0, 2.8, 4.0]);
+
+        let mut wrong_size = RenderSum::new(String::new(), 2, 2);
+        assert!(wrong_size.add_stored(&stored).is_err());
+    }
+}
@@ -1,8 +1,12 @@
-use std::{sync::Arc, time::Duration};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
 
-use worker::api::render_task::RenderTaskUninit;
+use worker::api::{
+    render_store::{GridFsRenderStore, LocalRenderStore, RenderStore},
+    render_task::RenderTaskUninit,
+};
 
 mod frame;
 mod scene;
@@ -12,10 +16,42 @@ mod worker_pool;
 use frame::Frame;
 use scene::Scene;
 
+const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Parser)]
 pub struct Cli {
+    // Scene files are uploaded there for workers using GridFS, workers without a file
+    // store fetch them from the client anyway.
+    #[clap(long, required_if_eq("render_store", "grid-fs"))]
+    mongodb_url: Option<String>,
+    // Workers outside of the broadcast domain, can be repeated.
+    #[clap(long = "worker", value_parser = worker_pool::parse_worker_address)]
+    workers: Vec<SocketAddr>,
+    // Grid-fs if the MongoDB url is set, local otherwise.
+    #[clap(long, value_enum)]
+    render_store: Option<RenderStoreType>,
+    // Root of the local render store.
+    #[clap(long, default_value = "./renders")]
+    render_dir: PathBuf,
+    // Only the last render of the task is kept, it holds all the samples anyway.
     #[clap(long)]
-    mongodb_url: String,
+    prune_renders: bool,
+}
+
+impl Cli {
+    fn render_store(&self) -> RenderStoreType {
+        match (self.render_store, &self.mongodb_url) {
+            (Some(render_store), _) => render_store,
+            (None, Some(_)) => RenderStoreType::GridFs,
+            (None, None) => RenderStoreType::Local,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum RenderStoreType {
+    GridFs,
+    Local,
 }
 
 #[tokio::main]
@@ -27,20 +63,39 @@ async fn main() {
     let render_task_data = String::from_utf8(render_task_data).unwrap();
     let render_task: RenderTaskUninit = serde_json::de::from_str(&render_task_data).unwrap();
 
-    let scene = Scene::load(&render_task.scene);
+    let scene = Scene::load(&render_task.scene)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to load scene: {:#}", err));
 
     let render_task = render_task.init(scene.md5.clone());
 
-    scene.upload_to_mongodb(&args.mongodb_url).await;
+    if let Some(mongodb_url) = &args.mongodb_url {
+        scene.upload_to_mongodb(mongodb_url).await;
+    }
+    let scene = Arc::new(scene);
 
+    let render_store = create_render_store(&args)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to create render store: {:#}", err));
     let frame = Frame::new(
         render_task.camera.resolution.x as u32,
         render_task.camera.resolution.y as u32,
+        render_store,
     )
     .await;
     let frame = Arc::from(frame);
 
-    let worker_pool = worker_pool::start(frame.clone());
+    tokio::spawn({
+        let frame = frame.clone();
+        async move {
+            loop {
+                tokio::time::sleep(SAVE_INTERVAL).await;
+                frame.save().await;
+            }
+        }
+    });
+
+    let worker_pool = worker_pool::start(frame.clone(), scene, args.workers);
     let wokrer_pool_clone = worker_pool.clone();
 
     tokio::spawn(async move {
@@ -50,5 +105,24 @@ async fn main() {
         }
     });
 
-    window::start(frame, worker_pool).unwrap();
+    let result = window::start(frame.clone(), worker_pool);
+    // Samples rendered since the last save would be lost otherwise.
+    frame.save().await;
+    result.unwrap();
+}
+
+async fn create_render_store(args: &Cli) -> anyhow::Result<Box<dyn RenderStore>> {
+    Ok(match args.render_store() {
+        RenderStoreType::GridFs => {
+            let mongodb_url = args
+                .mongodb_url
+                .as_ref()
+                .context("MongoDB url is required")?;
+            Box::new(GridFsRenderStore::connect(mongodb_url, args.prune_renders).await?)
+        }
+        RenderStoreType::Local => Box::new(LocalRenderStore::new(
+            args.render_dir.clone(),
+            args.prune_renders,
+        )),
+    })
 }
@@ -1,14 +1,18 @@
 use std::collections::{HashMap, HashSet};
 
+use anyhow::Context;
 use futures::{AsyncWriteExt, stream::StreamExt};
 use mongodb::{
-    bson::doc,
-    options::{GridFsBucketOptions, GridFsUploadOptions},
+    bson::{Document, doc},
+    options::{GridFsBucketOptions, UpdateOptions},
+};
+use worker::api::scene::{
+    Image, KdTree, Material, Mesh, Resource, ResourceType, SceneHierarchy, build_kd_trees,
 };
-use worker::api::scene::{Image, Material, Mesh, Resource, ResourceType, SceneHierarchy};
 
 struct FileReference {
     path: String,
+    md5: String,
 }
 
 pub struct Scene {
@@ -17,11 +21,41 @@ pub struct Scene {
 }
 
 impl Scene {
-    pub fn load(path: &str) -> Scene {
+    // Builds kd-trees referenced by the scene and writes the ones that changed to scene_data.
+    async fn update_kd_trees(path: &str) -> anyhow::Result<()> {
+        let read_file = |path: String, resource_type: Option<ResourceType>| async move {
+            let absolute_path = format!("./scene_data/{}", path);
+            match std::fs::read(&absolute_path) {
+                Ok(data) => Ok(data),
+                // Kd-tree files that don't exist yet are built from scratch.
+                Err(err)
+                    if resource_type == Some(ResourceType::KdTree)
+                        && err.kind() == std::io::ErrorKind::NotFound =>
+                {
+                    Ok(vec![])
+                }
+                Err(err) => Err(err).with_context(|| format!("Failed to read {}", absolute_path)),
+            }
+        };
+
+        for (tree_path, tree_data) in build_kd_trees(path, read_file).await? {
+            let absolute_path = format!("./scene_data/{}", tree_path);
+            if std::fs::read(&absolute_path).ok().as_ref() != Some(&tree_data) {
+                std::fs::write(&absolute_path, tree_data).unwrap();
+                println!("Kd-tree {} updated", tree_path);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load(path: &str) -> anyhow::Result<Scene> {
+        Self::update_kd_trees(path).await?;
+
         let absolute_path = format!("./scene_data/{}", path);
-        let scene_data = &std::fs::read(absolute_path).unwrap();
+        let scene_data = &std::fs::read(&absolute_path)
+            .with_context(|| format!("Failed to read {}", absolute_path))?;
         let scene_md5 = format!("{:x}", md5::compute(scene_data));
-        let scene_data = SceneHierarchy::load(scene_data);
+        let scene_data = SceneHierarchy::load(scene_data)?;
 
         let mut staged_to_load = scene_data.collect_references();
         let mut loaded = HashSet::from([path.to_string()]);
@@ -36,37 +70,38 @@ impl Scene {
                     .collect::<HashSet<_>>(),
             );
 
-            staged_to_load = staged_to_load
-                .into_iter()
-                .flat_map(|to_load| {
-                    let absolute_path = format!("./scene_data/{}", to_load.path);
-                    let data = &std::fs::read(absolute_path).unwrap();
-                    let md5 = format!("{:x}", md5::compute(data));
-                    md5s.insert(to_load.path.clone(), md5);
-                    match to_load.ty {
-                        ResourceType::Image => {
-                            let image = Image::load(data);
-                            image.collect_references()
-                        }
-                        ResourceType::Mesh => {
-                            let mesh = Mesh::load(data);
-                            mesh.collect_references()
-                        }
-                        ResourceType::Material => {
-                            let material = Material::load(data);
-                            material.collect_references()
-                        }
-                        ResourceType::KdTree => {
-                            unimplemented!()
-                        }
+            let mut references = HashSet::new();
+            for to_load in staged_to_load {
+                let absolute_path = format!("./scene_data/{}", to_load.path);
+                let data = &std::fs::read(&absolute_path)
+                    .with_context(|| format!("Failed to read {}", absolute_path))?;
+                let md5 = format!("{:x}", md5::compute(data));
+                md5s.insert(to_load.path.clone(), md5);
+                let context = || format!("Failed to load {} {}", to_load.ty, to_load.path);
+                references.extend(match to_load.ty {
+                    ResourceType::Image => Image::load(data)
+                        .with_context(context)?
+                        .collect_references(),
+                    ResourceType::Mesh => {
+                        Mesh::load(data).with_context(context)?.collect_references()
                     }
-                })
-                .collect();
+                    ResourceType::Material => Material::load(data)
+                        .with_context(context)?
+                        .collect_references(),
+                    ResourceType::KdTree => KdTree::load(data)
+                        .with_context(context)?
+                        .collect_references(),
+                });
+            }
+            staged_to_load = references;
         }
 
         let file_references = loaded
             .into_iter()
-            .map(|loaded| FileReference { path: loaded })
+            .map(|loaded| FileReference {
+                md5: md5s[&loaded].clone(),
+                path: loaded,
+            })
             .collect();
 
         let mut md5s: Vec<_> = md5s.into_iter().collect();
@@ -78,12 +113,45 @@ impl Scene {
                 format!("{:x}", md5::compute(acc + &x))
             });
 
-        Scene {
+        Ok(Scene {
             file_references,
             md5: resulting_md5,
+        })
+    }
+
+    fn file_reference(&self, path: &str) -> anyhow::Result<&FileReference> {
+        self.file_references
+            .iter()
+            .find(|reference| reference.path == path)
+            .with_context(|| format!("File {} isn't part of the scene", path))
+    }
+
+    pub fn file_md5(&self, path: &str) -> anyhow::Result<String> {
+        Ok(self.file_reference(path)?.md5.clone())
+    }
+
+    // Only files of the scene can be read, as long as they didn't change since it was loaded.
+    pub async fn read_file(&self, path: &str) -> anyhow::Result<Vec<u8>> {
+        let reference = self.file_reference(path)?;
+
+        let absolute_path = format!("./scene_data/{}", path);
+        let data = tokio::fs::read(&absolute_path)
+            .await
+            .with_context(|| format!("Failed to read {}", absolute_path))?;
+        // Hashing big files would stall other workers served by the runtime.
+        let (data, md5) = tokio::task::spawn_blocking(move || {
+            let md5 = format!("{:x}", md5::compute(&data));
+            (data, md5)
+        })
+        .await?;
+        if md5 != reference.md5 {
+            anyhow::bail!("File {} changed since the scene was loaded", path);
         }
+        Ok(data)
     }
 
+    // Contents of the files are kept once, named by their md5 in the shared bucket,
+    // while the scene collection maps paths of its files to their md5.
     pub async fn upload_to_mongodb(&self, mongodb_url: &str) {
         let mongodb_options = mongodb::options::ClientOptions::parse(mongodb_url)
             .await
@@ -93,61 +161,27 @@ impl Scene {
         let database = mongodb.database("scene_files");
         let bucket = database.gridfs_bucket(Some(
             GridFsBucketOptions::builder()
-                .bucket_name(self.md5.clone())
+                .bucket_name("files".to_string())
                 .build(),
         ));
-
-        // TODO: Upload only files that haven't yet been uploaded
+        let scene_files = database.collection::<Document>(&self.md5);
 
         for reference in &self.file_references {
-            let file_data = std::fs::read(format!("./scene_data/{}", reference.path)).unwrap();
-            let file_md5 = format!("{:x}", md5::compute(&file_data));
-
-            let mut found_files = bucket
-                .find(doc! { "filename": &reference.path }, None)
+            // Uploads are only found once closed, so interrupted ones are uploaded again.
+            let uploaded = bucket
+                .find(doc! { "filename": &reference.md5 }, None)
                 .await
                 .expect("TODO: propagate")
-                .collect::<Vec<_>>()
-                .await;
-
-            let upload_file = if !found_files.is_empty() {
-                assert_eq!(found_files.len(), 1);
-
-                let found_file = found_files.pop().unwrap().expect("TODO: propagate");
-                let found_file_metadata = found_file
-                    .metadata
-                    .expect("Extraneous file in database: Expected metadata");
-                let hash = found_file_metadata
-                    .get("md5")
-                    .unwrap_or_else(|| panic!(
-                        "Extraneous file in database: Wrong metadata format [{}], expected md5",
-                        found_file_metadata
-                    ))
-                    .as_str()
-                    .unwrap_or_else(|| panic!(
-                        "Extraneous file in database: Wrong metadata format [{}], expected md5 as string",
-                        found_file_metadata
-                    ));
-
-                if hash != file_md5 {
-                    bucket.delete(found_file.id).await.expect("TODO: propagate");
-                    true
-                } else {
-                    false
-                }
-            } else {
-                true
-            };
-
-            if upload_file {
-                let mut upload_stream = bucket.open_upload_stream(
-                    reference.path.clone(),
-                    Some(
-                        GridFsUploadOptions::builder()
-                            .metadata(Some(doc! { "md5": file_md5 }))
-                            .build(),
-                    ),
-                );
+                .next()
+                .await
+                .is_some();
+
+            if !uploaded {
+                let file_data = self
+                    .read_file(&reference.path)
+                    .await
+                    .unwrap_or_else(|err| panic!("Failed to upload file: {:#}", err));
+                let mut upload_stream = bucket.open_upload_stream(reference.md5.clone(), None);
 
                 upload_stream
                     .write_all(&file_data)
@@ -155,6 +189,15 @@ impl Scene {
                     .expect("TODO: propagate");
                 upload_stream.close().await.expect("TODO: propagate");
             }
+
+            scene_files
+                .update_one(
+                    doc! { "path": &reference.path },
+                    doc! { "$set": { "md5": &reference.md5 } },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await
+                .expect("TODO: propagate");
         }
     }
 }
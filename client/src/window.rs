@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::sync::Arc;
 
 use ::image::RgbaImage;
 use futures::StreamExt;
@@ -6,13 +6,15 @@ use iced::{
     Alignment, Element, Subscription, Task,
     advanced::image::Handle as ImageHandle,
     application::BootFn,
-    widget::{self, button, center, column, container, container::Style, image, row, text},
+    widget::{
+        self, button, center, column, container, container::Style, image, row, text, text_input,
+    },
 };
 use iced_aw::{TabLabel, Tabs};
 
 use crate::{
     frame::Frame,
-    worker_pool::{self},
+    worker_pool::{self, ReportedError, WorkerInfo},
 };
 
 pub fn start(frame: Arc<Frame>, worker_pool: worker_pool::Handle) -> iced::Result {
@@ -22,7 +24,10 @@ pub fn start(frame: Arc<Frame>, worker_pool: worker_pool::Handle) -> iced::Resul
             worker_pool,
             active_tab: Default::default(),
             render: None,
-            worker_addresses: vec![],
+            workers: vec![],
+            worker_errors: vec![],
+            new_worker_address: String::new(),
+            new_worker_error: None,
         },
         Layout::update,
         Layout::view,
@@ -38,15 +43,21 @@ struct Layout {
 
     active_tab: TabId,
     render: Option<RgbaImage>,
-    worker_addresses: Vec<String>,
+    workers: Vec<String>,
+    worker_errors: Vec<String>,
+    new_worker_address: String,
+    new_worker_error: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 enum Message {
     // TODO: Box RgbaImage.
     NewRender(RgbaImage),
-    WorkerPoolStatsChanged(Vec<SocketAddr>),
+    WorkerPoolStatsChanged(Vec<WorkerInfo>),
+    WorkerErrorsChanged(Vec<ReportedError>),
     StartWorkerDiscovery,
+    NewWorkerAddressChanged(String),
+    AddWorker,
     TabSelected(TabId),
 }
 
@@ -65,7 +76,10 @@ impl BootFn<Layout, Message> for Layout {
                 worker_pool: self.worker_pool.clone(),
                 active_tab: Default::default(),
                 render: None,
-                worker_addresses: vec![],
+                workers: vec![],
+                worker_errors: vec![],
+                new_worker_address: String::new(),
+                new_worker_error: None,
             },
             Task::none(),
         )
@@ -82,15 +96,31 @@ impl Layout {
             Message::NewRender(render) => {
                 self.render = Some(render);
             }
-            Message::WorkerPoolStatsChanged(addresses) => {
-                self.worker_addresses = addresses
+            Message::WorkerPoolStatsChanged(workers) => {
+                self.workers = workers
                     .into_iter()
-                    .map(|address| format!("{address}"))
+                    .map(|worker| format!("{} {}", worker.address, worker.status))
                     .collect();
             }
+            Message::WorkerErrorsChanged(errors) => {
+                self.worker_errors = errors.iter().map(ToString::to_string).collect();
+            }
             Message::StartWorkerDiscovery => {
                 self.worker_pool.discover();
             }
+            Message::NewWorkerAddressChanged(address) => {
+                self.new_worker_address = address;
+                self.new_worker_error = None;
+            }
+            Message::AddWorker => {
+                match worker_pool::parse_worker_address(&self.new_worker_address) {
+                    Ok(address) => {
+                        self.worker_pool.add_worker(address);
+                        self.new_worker_address.clear();
+                    }
+                    Err(err) => self.new_worker_error = Some(err),
+                }
+            }
             Message::TabSelected(tab) => {
                 self.active_tab = tab;
             }
@@ -100,9 +130,12 @@ impl Layout {
     fn subscription(&self) -> Subscription<Message> {
         Subscription::batch(vec![
             Subscription::run_with(self.worker_pool.clone(), |pool| {
-                pool.get_worker_discovery_stream()
+                pool.get_worker_stream()
                     .map(Message::WorkerPoolStatsChanged)
             }),
+            Subscription::run_with(self.worker_pool.clone(), |pool| {
+                pool.get_error_stream().map(Message::WorkerErrorsChanged)
+            }),
             Subscription::run_with(self.frame.clone(), |frame| {
                 frame.clone().get_image_stream().map(Message::NewRender)
             }),
@@ -114,19 +147,23 @@ impl Layout {
             .push(
                 TabId::Render,
                 TabLabel::Text("render".to_string()),
-                render_tab(&self.render),
+                render_tab(&self.render, &self.worker_errors),
             )
             .push(
                 TabId::Workers,
                 TabLabel::Text("workers".to_string()),
-                workers_tab(&self.worker_addresses),
+                workers_tab(
+                    &self.workers,
+                    &self.new_worker_address,
+                    &self.new_worker_error,
+                ),
             )
             .set_active_tab(&self.active_tab)
             .into()
     }
 }
 
-fn render_tab(render: &Option<RgbaImage>) -> Element<'_, Message> {
+fn render_tab<'a>(render: &'a Option<RgbaImage>, errors: &'a [String]) -> Element<'a, Message> {
     let render = match render {
         Some(render) => column![image(ImageHandle::from_rgba(
             render.width(),
@@ -136,20 +173,41 @@ fn render_tab(render: &Option<RgbaImage>) -> Element<'_, Message> {
         None => column![],
     };
 
+    let errors = errors
+        .iter()
+        .map(|error| text(error).style(text::danger).into());
+    let render = render.push(column(errors).spacing(4)).spacing(8);
+
     center(render).padding(10).into()
 }
 
-fn workers_tab(addresses: &[String]) -> Element<'_, Message> {
+fn workers_tab<'a>(
+    workers: &'a [String],
+    new_worker_address: &'a str,
+    new_worker_error: &'a Option<String>,
+) -> Element<'a, Message> {
     let discover = button("discover workers").on_press(Message::StartWorkerDiscovery);
-    let discover = container(discover)
+    let new_worker = row![
+        text_input("ip:port", new_worker_address)
+            .on_input(Message::NewWorkerAddressChanged)
+            .on_submit(Message::AddWorker)
+            .width(160),
+        button("add worker").on_press(Message::AddWorker),
+    ]
+    .spacing(8);
+    let mut controls = column![discover, new_worker].spacing(8);
+    if let Some(error) = new_worker_error {
+        controls = controls.push(text(error).style(text::danger));
+    }
+    let controls = container(controls)
         .padding(8)
         .align_x(Alignment::Start)
         .align_y(Alignment::Start);
 
-    let entries: Vec<_> = addresses
+    let entries: Vec<_> = workers
         .iter()
-        .map(|address| {
-            container(&**address)
+        .map(|worker| {
+            container(&**worker)
                 .padding(8)
                 .style(|theme| Style {
                     border: iced::Border::default().rounded(8),
@@ -159,11 +217,11 @@ fn workers_tab(addresses: &[String]) -> Element<'_, Message> {
         })
         .collect();
 
-    let worker_list = if addresses.is_empty() {
+    let worker_list = if workers.is_empty() {
         center(text("No workers found"))
     } else {
         center(column(entries).spacing(8))
     };
 
-    row![discover, worker_list].into()
+    row![controls, worker_list].into()
 }
@@ -1,59 +1,106 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, VecDeque},
+    fmt::Display,
     hash::Hash,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    sync::Arc,
-    time::Duration,
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
 use futures::{SinkExt, StreamExt};
+use math::UVec2;
 use tokio::{
     net::{TcpStream, UdpSocket},
-    sync::{RwLock, mpsc, watch},
+    sync::{Mutex, MutexGuard, Notify, mpsc, watch},
     time::timeout,
 };
 use tokio_stream::wrappers::WatchStream;
 use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async, tungstenite::Message};
 use worker::{
-    RenderedImage,
-    api::render_task::RenderTask,
-    discovery::{Request as DiscoveryRequest, Response as DiscoveryResponse},
+    api::{
+        message::{PROTOCOL_VERSION, ProgressInterval, Request, Response, WorkerError},
+        render_task::{Crop, RenderTask},
+    },
+    discovery::{Capabilities, Request as DiscoveryRequest, Response as DiscoveryResponse},
 };
 
-use crate::frame::Frame;
+use crate::{frame::Frame, scene::Scene};
 
 const DISCOVERY_TIMEOUT: Duration = Duration::from_secs(5);
 const DISCOVERY_PORT: u16 = 40000;
+// Used for added workers whose address has no port.
+const DEFAULT_WORKER_PORT: u16 = 30000;
 const WORKERS_DISCOVERY_CHANNEL_BUFFER: usize = 8;
+const TILE_SIZE: usize = 64;
+// Workers get jobs sized to take about this long, according to their measured throughput.
+const TARGET_JOB_DURATION: Duration = Duration::from_secs(2);
+// Jobs longer than this show progress before they're finished.
+const PROGRESS_INTERVAL_SECONDS: f32 = 0.5;
+const MAX_REPORTED_ERRORS: usize = 8;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Workers are pinged this often, they're lost if they don't answer within the timeout.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(5);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
 
 type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
-pub fn start(frame: Arc<Frame>) -> Handle {
+// Static workers are connected right away, others are found by discovery or added later.
+pub fn start(frame: Arc<Frame>, scene: Arc<Scene>, static_workers: Vec<SocketAddr>) -> Handle {
     let (discovery_requests_sender, discovery_requests_receiver) = watch::channel(());
-    let (discovered_workers_sender, discovered_workers_receiver) = watch::channel(vec![]);
+    let (added_workers_sender, added_workers_receiver) = mpsc::unbounded_channel();
+    let (workers_sender, workers_receiver) = watch::channel(vec![]);
     let (render_tasks_sender, render_tasks_receiver) = mpsc::channel(1);
+    let (errors_sender, errors_receiver) = watch::channel(vec![]);
 
     let pool = Pool::new(
         frame,
+        scene,
         discovery_requests_receiver,
-        discovered_workers_sender,
+        added_workers_receiver,
+        workers_sender,
         render_tasks_receiver,
+        ErrorLog(errors_sender),
     );
     tokio::spawn(pool.run());
 
-    Handle {
+    let handle = Handle {
         discovery_requests: discovery_requests_sender,
+        added_workers: added_workers_sender,
         render_tasks_queue: render_tasks_sender,
-        discovered_workers: discovered_workers_receiver,
+        workers: workers_receiver,
+        errors: errors_receiver,
+    };
+    for address in static_workers {
+        handle.add_worker(address);
     }
+    handle
+}
+
+// Accepts "ip:port", or just "ip" for workers listening on the default port.
+pub fn parse_worker_address(address: &str) -> Result<SocketAddr, String> {
+    let address = address.trim();
+    if let Ok(address) = address.parse() {
+        return Ok(address);
+    }
+    address
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DEFAULT_WORKER_PORT))
+        .map_err(|_| format!("Invalid worker address: {}", address))
 }
 
 #[derive(Clone)]
 pub struct Handle {
     discovery_requests: watch::Sender<()>,
+    added_workers: mpsc::UnboundedSender<SocketAddr>,
     render_tasks_queue: mpsc::Sender<RenderTask>,
-    discovered_workers: watch::Receiver<Vec<SocketAddr>>,
+    workers: watch::Receiver<Vec<WorkerInfo>>,
+    errors: watch::Receiver<Vec<ReportedError>>,
 }
 
 impl Hash for Handle {
@@ -65,6 +112,11 @@ impl Handle {
         self.discovery_requests.send(()).unwrap();
     }
 
+    // For workers that can't be reached by the discovery broadcast.
+    pub fn add_worker(&self, address: SocketAddr) {
+        self.added_workers.send(address).unwrap();
+    }
+
     pub fn send_render_task(&self, render_task: RenderTask) -> Result<(), ()> {
         // TODO: Properly handle 2 types of error here.
         self.render_tasks_queue
@@ -72,8 +124,68 @@ impl Handle {
             .map_err(|_| ())
     }
 
-    pub fn get_worker_discovery_stream(&self) -> WatchStream<Vec<SocketAddr>> {
-        WatchStream::new(self.discovered_workers.clone())
+    pub fn get_worker_stream(&self) -> WatchStream<Vec<WorkerInfo>> {
+        WatchStream::new(self.workers.clone())
+    }
+
+    pub fn get_error_stream(&self) -> WatchStream<Vec<ReportedError>> {
+        WatchStream::new(self.errors.clone())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerInfo {
+    pub address: SocketAddr,
+    pub status: WorkerStatus,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerStatus {
+    Connecting,
+    Idle,
+    Busy,
+    // Connection failed or the worker stopped answering, it's reconnected after a delay.
+    Lost,
+}
+
+impl Display for WorkerStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            WorkerStatus::Connecting => "connecting",
+            WorkerStatus::Idle => "idle",
+            WorkerStatus::Busy => "busy",
+            WorkerStatus::Lost => "lost",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ReportedError {
+    pub address: SocketAddr,
+    pub error: WorkerError,
+}
+
+impl Display for ReportedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.address, self.error)
+    }
+}
+
+// Latest errors reported by workers, older ones are dropped.
+#[derive(Clone)]
+struct ErrorLog(watch::Sender<Vec<ReportedError>>);
+
+impl ErrorLog {
+    fn report(&self, address: SocketAddr, error: WorkerError) {
+        let error = ReportedError { address, error };
+        println!("Worker error: {}", error);
+        self.0.send_modify(|errors| {
+            errors.push(error);
+            if errors.len() > MAX_REPORTED_ERRORS {
+                errors.remove(0);
+            }
+        });
     }
 }
 
@@ -85,21 +197,29 @@ struct Pool {
 impl Pool {
     fn new(
         frame: Arc<Frame>,
+        scene: Arc<Scene>,
         discovery_requests: watch::Receiver<()>,
-        discovered_workers_watch: watch::Sender<Vec<SocketAddr>>,
+        added_workers: mpsc::UnboundedReceiver<SocketAddr>,
+        workers_watch: watch::Sender<Vec<WorkerInfo>>,
         render_tasks: mpsc::Receiver<RenderTask>,
+        errors: ErrorLog,
     ) -> Pool {
-        let workers = Arc::from(RwLock::new(HashSet::new()));
         let (discovered_workers_sender, discovered_workers_receiver) =
             mpsc::channel(WORKERS_DISCOVERY_CHANNEL_BUFFER);
 
         let finder = Finder {
-            workers: workers.clone(),
             discovery_requests,
-            discovered_workers_watch,
+            added_workers: Mutex::new(added_workers),
             discovered_workers_sender,
         };
-        let scheduler = Scheduler::new(discovered_workers_receiver, frame, render_tasks);
+        let scheduler = Scheduler::new(
+            discovered_workers_receiver,
+            WorkerStates::new(workers_watch),
+            frame,
+            scene,
+            render_tasks,
+            errors,
+        );
 
         Pool { finder, scheduler }
     }
@@ -109,20 +229,32 @@ impl Pool {
 
         tokio::select! {
             _ = finder.run_discovery(DISCOVERY_PORT) => {},
+            _ = finder.run_added_workers() => {},
             _ = scheduler.schedule_render_tasks() => {}
         };
     }
 }
 
 struct Finder {
-    workers: Arc<RwLock<HashSet<WorkerDescriptor>>>,
-
     discovery_requests: watch::Receiver<()>,
-    discovered_workers_watch: watch::Sender<Vec<SocketAddr>>,
+    added_workers: Mutex<mpsc::UnboundedReceiver<SocketAddr>>,
     discovered_workers_sender: mpsc::Sender<WorkerDescriptor>,
 }
 
 impl Finder {
+    // Added workers are merged with discovered ones, their capabilities come from the handshake.
+    async fn run_added_workers(&self) {
+        let mut added_workers = self.added_workers.lock().await;
+        while let Some(address) = added_workers.recv().await {
+            println!("Worker added: {}", address);
+            let worker = WorkerDescriptor {
+                address,
+                capabilities: None,
+            };
+            self.discovered_workers_sender.send(worker).await.unwrap();
+        }
+    }
+
     async fn run_discovery(&self, port: u16) {
         let mut discovery_requests = self.discovery_requests.clone();
         loop {
@@ -166,26 +298,31 @@ impl Finder {
 
         let worker = WorkerDescriptor {
             address: worker_address,
+            capabilities: Some(response.capabilities.clone()),
         };
 
-        println!("Worker discovered: {}", worker_address);
-
-        let mut workers = self.workers.read().await.clone();
-        workers.insert(worker.clone());
-        *(self.workers.write().await) = workers.clone();
+        println!(
+            "Worker discovered: {} ({} threads, {} renderer, version {})",
+            worker_address,
+            response.capabilities.thread_count,
+            response.capabilities.renderer,
+            response.capabilities.version
+        );
 
         self.discovered_workers_sender.send(worker).await.unwrap();
-
-        self.discovered_workers_watch
-            .send(workers.iter().map(|worker| worker.address).collect())
-            .unwrap();
     }
 }
 
 struct Scheduler {
     discovered_workers: mpsc::Receiver<WorkerDescriptor>,
-    workers: Arc<RwLock<HashMap<WorkerDescriptor, Worker>>>,
+    workers: WorkerStates,
+    jobs: Arc<JobQueue>,
+    // Md5 of the task being rendered, jobs of other tasks are obsolete.
+    current_task: watch::Sender<String>,
     frame: Arc<Frame>,
+    // Workers without a file store fetch its files from the client.
+    scene: Arc<Scene>,
+    errors: ErrorLog,
 
     render_tasks: mpsc::Receiver<RenderTask>,
 }
@@ -193,95 +330,723 @@ struct Scheduler {
 impl Scheduler {
     fn new(
         discovered_workers: mpsc::Receiver<WorkerDescriptor>,
+        workers: WorkerStates,
         frame: Arc<Frame>,
+        scene: Arc<Scene>,
         render_tasks: mpsc::Receiver<RenderTask>,
+        errors: ErrorLog,
     ) -> Self {
         Self {
             discovered_workers,
-            workers: Arc::from(RwLock::new(HashMap::new())),
+            workers,
+            jobs: Arc::from(JobQueue::default()),
+            current_task: watch::channel(String::new()).0,
             frame,
+            scene,
+            errors,
             render_tasks,
         }
     }
 
     async fn schedule_render_tasks(mut self) {
         let mut discovered_workers = self.discovered_workers;
-        let workers_map = self.workers.clone();
+        let workers = self.workers.clone();
+        let jobs = self.jobs.clone();
+        let current_task = self.current_task.subscribe();
+        let frame = self.frame.clone();
+        let scene = self.scene.clone();
+        let errors = self.errors.clone();
         tokio::spawn(async move {
             loop {
-                let worker_descriptor = discovered_workers.recv().await.unwrap();
-                let worker = Worker::connect(&worker_descriptor).await;
-                workers_map
-                    .write()
-                    .await
-                    .insert(worker_descriptor.clone(), worker);
+                let descriptor = discovered_workers.recv().await.unwrap();
+                // Known workers are already kept connected.
+                if !workers.add(&descriptor).await {
+                    continue;
+                }
+
+                tokio::spawn(Worker::keep_connected(
+                    descriptor.address,
+                    jobs.clone(),
+                    workers.clone(),
+                    current_task.clone(),
+                    frame.clone(),
+                    scene.clone(),
+                    errors.clone(),
+                ));
             }
         });
 
+        let mut next_task = None;
+        loop {
+            let mut task = match next_task.take() {
+                Some(task) => task,
+                None => self.render_tasks.recv().await.unwrap(),
+            };
+
+            let md5 = task.md5();
+            if *self.current_task.borrow() != md5 {
+                // Workers cancel obsolete jobs when the current task changes.
+                self.jobs.clear().await;
+                let render_count = self.frame.reset(&task).await;
+                // Stored renders were rendered with seeds below this offset, as every save
+                // increases it.
+                self.jobs
+                    .reset_seeds(task.config.seed.wrapping_add((render_count as u64) << 32));
+                self.current_task.send_replace(md5.clone());
+            }
+
+            // Every pass gets its own seed, otherwise passes would render the same samples.
+            task.config.seed = self.jobs.take_seed();
+
+            self.jobs.push(split_into_tiles(&task)).await;
+
+            // Next pass is accepted once all the tiles of this one are taken by workers,
+            // a changed task replaces it right away.
+            loop {
+                tokio::select! {
+                    _ = self.jobs.wait_empty() => break,
+                    task = self.render_tasks.recv() => {
+                        let task = task.unwrap();
+                        if task.md5() != md5 {
+                            next_task = Some(task);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Jobs waiting for a worker, every worker takes the next one as soon as it's idle.
+#[derive(Default)]
+struct JobQueue {
+    jobs: Mutex<VecDeque<RenderTask>>,
+    job_added: Notify,
+    job_taken: Notify,
+    // Seed of the next pass or requeued job.
+    next_seed: AtomicU64,
+}
+
+impl JobQueue {
+    async fn push(&self, jobs: impl IntoIterator<Item = RenderTask>) {
+        self.jobs.lock().await.extend(jobs);
+        self.job_added.notify_waiters();
+    }
+
+    async fn clear(&self) {
+        self.jobs.lock().await.clear();
+        self.job_taken.notify_waiters();
+    }
+
+    fn reset_seeds(&self, seed: u64) {
+        self.next_seed.store(seed, Ordering::Relaxed);
+    }
+
+    fn take_seed(&self) -> u64 {
+        self.next_seed.fetch_add(1, Ordering::Relaxed)
+    }
+
+    // Returned jobs are rendered before all the others. They get a new seed, so samples
+    // merged from their progress aren't rendered again.
+    async fn requeue(&self, mut job: RenderTask) {
+        job.config.seed = self.take_seed();
+        self.jobs.lock().await.push_front(job);
+        self.job_added.notify_waiters();
+    }
+
+    // Neighbouring tiles are merged into a single job while it fits into the sample budget.
+    // Only a single tile is taken if there's no budget.
+    async fn pop(&self, sample_budget: Option<f32>) -> RenderTask {
+        loop {
+            // Created before checking the queue so no notification is missed.
+            let job_added = self.job_added.notified();
+            let mut jobs = self.jobs.lock().await;
+            if let Some(mut job) = jobs.pop_front() {
+                while let Some(next) = jobs.front() {
+                    let fits_budget = sample_budget.is_some_and(|sample_budget| {
+                        (sample_count(&job) + sample_count(next)) as f32 <= sample_budget
+                    });
+                    let Some(merged) = merge_tiles(&job, next).filter(|_| fits_budget) else {
+                        break;
+                    };
+                    job = merged;
+                    jobs.pop_front();
+                }
+                drop(jobs);
+
+                self.job_taken.notify_waiters();
+                return job;
+            }
+            drop(jobs);
+            job_added.await;
+        }
+    }
+
+    async fn wait_empty(&self) {
         loop {
-            let task = self.render_tasks.recv().await.unwrap();
-
-            let mut workers: Vec<_> = self.workers.write().await.drain().collect();
-            // TODO: Distribute render tasks.
-            // TODO: Parallelize.
-            for i in (0..workers.len()).rev() {
-                let (_, worker) = &mut workers[i];
-                if let Err(err) = worker.get_image(task.clone(), self.frame.clone()).await {
-                    // TODO: Notify the worker pool user about disconnected workers.
-                    println!("Error during message exchange: {}", err);
-                    workers.remove(i);
-                };
+            let job_taken = self.job_taken.notified();
+            if self.jobs.lock().await.is_empty() {
+                return;
             }
+            job_taken.await;
+        }
+    }
+}
+
+fn sample_count(task: &RenderTask) -> usize {
+    let crop = task.crop();
+    crop.size.x * crop.size.y * task.config.iterations
+}
 
-            self.workers.write().await.extend(workers);
+// Merges horizontally adjacent tiles of the same height.
+fn merge_tiles(left: &RenderTask, right: &RenderTask) -> Option<RenderTask> {
+    let (left_crop, right_crop) = (left.crop(), right.crop());
+    let adjacent = left_crop.offset.y == right_crop.offset.y
+        && left_crop.size.y == right_crop.size.y
+        && left_crop.offset.x + left_crop.size.x == right_crop.offset.x;
+    if !adjacent || left.config.seed != right.config.seed {
+        return None;
+    }
+
+    let mut merged = left.clone();
+    merged.crop = Some(Crop {
+        offset: left_crop.offset,
+        size: UVec2::new(left_crop.size.x + right_crop.size.x, left_crop.size.y),
+    });
+    Some(merged)
+}
+
+fn split_into_tiles(task: &RenderTask) -> VecDeque<RenderTask> {
+    let crop = task.crop();
+    let mut tiles = VecDeque::new();
+    for y in (0..crop.size.y).step_by(TILE_SIZE) {
+        for x in (0..crop.size.x).step_by(TILE_SIZE) {
+            let mut tile = task.clone();
+            tile.crop = Some(Crop {
+                offset: UVec2::new(crop.offset.x + x, crop.offset.y + y),
+                size: UVec2::new(
+                    TILE_SIZE.min(crop.size.x - x),
+                    TILE_SIZE.min(crop.size.y - y),
+                ),
+            });
+            tiles.push_back(tile);
         }
     }
+    tiles
 }
 
-#[derive(PartialEq, Eq, Hash, Clone)]
 struct WorkerDescriptor {
     address: SocketAddr,
+    capabilities: Option<Capabilities>,
+}
+
+// States of all the known workers, the pool user is notified about every status change.
+#[derive(Clone)]
+struct WorkerStates {
+    states: Arc<Mutex<HashMap<SocketAddr, WorkerState>>>,
+    watch: watch::Sender<Vec<WorkerInfo>>,
+}
+
+impl WorkerStates {
+    fn new(watch: watch::Sender<Vec<WorkerInfo>>) -> WorkerStates {
+        WorkerStates {
+            states: Arc::default(),
+            watch,
+        }
+    }
+
+    async fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, WorkerState>> {
+        self.states.lock().await
+    }
+
+    // Returns false if the worker is already known.
+    async fn add(&self, descriptor: &WorkerDescriptor) -> bool {
+        let mut states = self.states.lock().await;
+        if states.contains_key(&descriptor.address) {
+            return false;
+        }
+        let state = WorkerState {
+            status: WorkerStatus::Connecting,
+            capabilities: descriptor.capabilities.clone(),
+            job: None,
+            samples_per_second: None,
+        };
+        states.insert(descriptor.address, state);
+        self.publish(&states);
+        true
+    }
+
+    async fn set_status(&self, address: SocketAddr, status: WorkerStatus) {
+        let mut states = self.states.lock().await;
+        if let Some(state) = states.get_mut(&address) {
+            state.status = status;
+        }
+        self.publish(&states);
+    }
+
+    fn publish(&self, states: &HashMap<SocketAddr, WorkerState>) {
+        let mut workers: Vec<_> = states
+            .iter()
+            .map(|(&address, state)| WorkerInfo {
+                address,
+                status: state.status,
+            })
+            .collect();
+        workers.sort_by_key(|worker| worker.address);
+        self.watch.send_replace(workers);
+    }
+}
+
+struct WorkerState {
+    status: WorkerStatus,
+    // Reported by the worker in discovery and then in the handshake.
+    capabilities: Option<Capabilities>,
+    job: Option<RenderTask>,
+    samples_per_second: Option<f32>,
+}
+
+impl WorkerState {
+    // Workers that haven't finished any job yet are estimated from the thread count
+    // and the per-thread throughput of the measured ones.
+    fn estimate_samples_per_second(
+        address: SocketAddr,
+        workers: &HashMap<SocketAddr, WorkerState>,
+    ) -> Option<f32> {
+        let state = workers.get(&address)?;
+        if let Some(samples_per_second) = state.samples_per_second {
+            return Some(samples_per_second);
+        }
+
+        let thread_count = state.capabilities.as_ref()?.thread_count;
+        let (measured_threads, measured_samples_per_second) = workers
+            .values()
+            .filter_map(|state| {
+                Some((
+                    state.capabilities.as_ref()?.thread_count,
+                    state.samples_per_second?,
+                ))
+            })
+            .fold((0, 0.0), |acc, (threads, samples_per_second)| {
+                (acc.0 + threads, acc.1 + samples_per_second)
+            });
+        if measured_threads == 0 {
+            return None;
+        }
+        Some(measured_samples_per_second / measured_threads as f32 * thread_count as f32)
+    }
 }
 
 struct Worker {
     connection: WsStream,
+    address: SocketAddr,
+    scene: Arc<Scene>,
+    errors: ErrorLog,
 }
 
 impl Worker {
-    async fn connect(descriptor: &WorkerDescriptor) -> Self {
-        let url = format!("ws://{}", descriptor.address);
+    // Capabilities are reported by the worker in the handshake.
+    async fn connect(
+        address: SocketAddr,
+        scene: Arc<Scene>,
+        errors: ErrorLog,
+    ) -> anyhow::Result<(Self, Capabilities)> {
+        let url = format!("ws://{}", address);
         println!("Connecting to worker {}", url);
-        let connection = connect_async(url).await.unwrap().0;
+        let connection = timeout(CONNECT_TIMEOUT, connect_async(url))
+            .await
+            .context("Connection timed out")?
+            .context("Failed to connect to worker")?
+            .0;
+
+        let mut worker = Self {
+            connection,
+            address,
+            scene,
+            errors,
+        };
+        worker
+            .send(Request::Hello {
+                version: PROTOCOL_VERSION,
+            })
+            .await?;
+        let response = timeout(HEARTBEAT_TIMEOUT, worker.receive())
+            .await
+            .context("Worker didn't answer hello")??;
+        match response {
+            Response::Hello { capabilities, .. } => Ok((worker, capabilities)),
+            Response::Error(error) => {
+                worker.errors.report(worker.address, error.clone());
+                anyhow::bail!("Worker rejected connection: {}", error)
+            }
+            _ => anyhow::bail!("Unexpected response to hello"),
+        }
+    }
 
-        Self { connection }
+    // Reconnects lost workers with growing delays, their jobs are given to other workers.
+    async fn keep_connected(
+        address: SocketAddr,
+        jobs: Arc<JobQueue>,
+        workers: WorkerStates,
+        current_task: watch::Receiver<String>,
+        frame: Arc<Frame>,
+        scene: Arc<Scene>,
+        errors: ErrorLog,
+    ) {
+        let mut reconnect_delay = MIN_RECONNECT_DELAY;
+        loop {
+            workers.set_status(address, WorkerStatus::Connecting).await;
+            match Self::connect(address, scene.clone(), errors.clone()).await {
+                Ok((worker, capabilities)) => {
+                    if let Some(state) = workers.lock().await.get_mut(&address) {
+                        state.capabilities = Some(capabilities);
+                    }
+                    reconnect_delay = MIN_RECONNECT_DELAY;
+
+                    let err = worker
+                        .run(
+                            jobs.clone(),
+                            workers.clone(),
+                            current_task.clone(),
+                            frame.clone(),
+                        )
+                        .await;
+                    println!("Lost worker {}: {:#}", address, err);
+                }
+                Err(err) => println!("Failed to connect to worker {}: {:#}", address, err),
+            }
+
+            workers.set_status(address, WorkerStatus::Lost).await;
+            tokio::time::sleep(reconnect_delay).await;
+            reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+
+    // Renders jobs until the connection fails.
+    async fn run(
+        mut self,
+        jobs: Arc<JobQueue>,
+        workers: WorkerStates,
+        mut current_task: watch::Receiver<String>,
+        frame: Arc<Frame>,
+    ) -> anyhow::Error {
+        loop {
+            workers.set_status(self.address, WorkerStatus::Idle).await;
+            let samples_per_second =
+                WorkerState::estimate_samples_per_second(self.address, &*workers.lock().await);
+            let sample_budget = samples_per_second
+                .map(|samples_per_second| samples_per_second * TARGET_JOB_DURATION.as_secs_f32());
+            // Idle workers are pinged, so lost ones don't take jobs.
+            let job = loop {
+                tokio::select! {
+                    job = jobs.pop(sample_budget) => break job,
+                    _ = tokio::time::sleep(HEARTBEAT_INTERVAL) => {
+                        if let Err(err) = self.ping().await {
+                            return err;
+                        }
+                    }
+                }
+            };
+            if let Some(state) = workers.lock().await.get_mut(&self.address) {
+                state.job = Some(job.clone());
+            }
+            workers.set_status(self.address, WorkerStatus::Busy).await;
+
+            let start = Instant::now();
+            let mut merged_iterations = 0;
+            let result = self
+                .get_image(
+                    job.clone(),
+                    &mut merged_iterations,
+                    &mut current_task,
+                    frame.clone(),
+                )
+                .await;
+            let elapsed = start.elapsed().as_secs_f32();
+
+            if let Some(state) = workers.lock().await.get_mut(&self.address) {
+                state.job = None;
+            }
+            if let Err(err) = result {
+                // Iterations merged from the progress aren't rendered again.
+                if job.md5() == *current_task.borrow() && merged_iterations < job.config.iterations
+                {
+                    let mut remaining = job;
+                    remaining.config.iterations -= merged_iterations;
+                    jobs.requeue(remaining).await;
+                }
+                return err;
+            }
+
+            if let Some(state) = workers.lock().await.get_mut(&self.address) {
+                let measured = sample_count(&job) as f32 / elapsed.max(f32::EPSILON);
+                // Smoothed, first jobs also include scene loading.
+                state.samples_per_second = Some(match state.samples_per_second {
+                    Some(samples_per_second) => (samples_per_second + measured) * 0.5,
+                    None => measured,
+                });
+            }
+        }
     }
 
+    async fn ping(&mut self) -> anyhow::Result<()> {
+        self.send(Request::Ping).await?;
+        timeout(HEARTBEAT_TIMEOUT, async {
+            loop {
+                if let Response::Pong = self.receive().await? {
+                    return Ok(());
+                }
+            }
+        })
+        .await
+        .context("Worker stopped responding")?
+    }
+
+    // Cancels the render if the current task changes while it's running.
+    // Iterations merged into the frame are counted even if the job fails later.
     async fn get_image(
         &mut self,
         render_task: RenderTask,
+        merged_iterations: &mut usize,
+        current_task: &mut watch::Receiver<String>,
         frame: Arc<Frame>,
     ) -> anyhow::Result<()> {
-        let render_task =
-            serde_json::to_string(&render_task).expect("Failed to serialze render task");
+        let task_md5 = render_task.md5();
+        let trace_depth = render_task.config.trace_depth;
+        self.send(Request::Render {
+            render_task: Box::new(render_task),
+            progress: Some(ProgressInterval {
+                iterations: None,
+                seconds: Some(PROGRESS_INTERVAL_SECONDS),
+            }),
+        })
+        .await?;
+
+        let mut cancelled = false;
+        let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+        heartbeat.reset();
+        let mut last_response = Instant::now();
+        // Paths traced and cut at the trace depth over all images of the job.
+        let mut paths = (0, 0);
+        loop {
+            let response = tokio::select! {
+                response = self.receive() => response?,
+                _ = heartbeat.tick() => {
+                    if last_response.elapsed() > HEARTBEAT_INTERVAL + HEARTBEAT_TIMEOUT {
+                        anyhow::bail!("Worker stopped responding");
+                    }
+                    self.send(Request::Ping).await?;
+                    continue;
+                }
+                Ok(()) = current_task.changed(), if !cancelled => {
+                    if *current_task.borrow_and_update() != task_md5 {
+                        self.send(Request::Cancel { keep_partial: false }).await?;
+                        cancelled = true;
+                    }
+                    continue;
+                }
+            };
+
+            last_response = Instant::now();
+
+            if let Response::Progress(image) | Response::Rendered(image) = &response {
+                let pixels = image.image.width() as u64 * image.image.height() as u64;
+                paths.0 += pixels * image.sample_count as u64;
+                paths.1 += image.capped_paths;
+                // Images hold only the samples rendered since the previous one.
+                *merged_iterations += image.sample_count as usize;
+            }
+
+            match response {
+                Response::Progress(image) => frame.add_render(&task_md5, image).await,
+                Response::Rendered(image) => {
+                    frame.add_render(&task_md5, image).await;
+                    if paths.1 != 0 {
+                        println!(
+                            "{} of {} paths rendered by {} reached trace depth of {}",
+                            paths.1, paths.0, self.address, trace_depth
+                        );
+                    }
+                    return Ok(());
+                }
+                Response::Cancelled => return Ok(()),
+                // The job is dropped, the worker stays connected.
+                Response::Error(error) => {
+                    self.errors.report(self.address, error);
+                    return Ok(());
+                }
+                Response::Pong => {}
+                Response::FetchFile { scene_md5, path } => {
+                    let data = match self.scene(&scene_md5) {
+                        Ok(scene) => scene.read_file(&path).await,
+                        Err(err) => Err(err),
+                    };
+                    let data = data.map_err(|err| format!("{:#}", err));
+                    self.send(Request::File {
+                        scene_md5,
+                        path,
+                        data,
+                    })
+                    .await?;
+                }
+                Response::FetchFileMd5 { scene_md5, path } => {
+                    let md5 = self
+                        .scene(&scene_md5)
+                        .and_then(|scene| scene.file_md5(&path))
+                        .map_err(|err| format!("{:#}", err));
+                    self.send(Request::FileMd5 {
+                        scene_md5,
+                        path,
+                        md5,
+                    })
+                    .await?;
+                }
+                Response::Hello { .. } => anyhow::bail!("Unexpected hello"),
+            }
+        }
+    }
+
+    fn scene(&self, scene_md5: &str) -> anyhow::Result<&Scene> {
+        if scene_md5 != self.scene.md5 {
+            anyhow::bail!("Scene {} isn't loaded by the client", scene_md5);
+        }
+        Ok(&self.scene)
+    }
+
+    async fn send(&mut self, request: Request) -> anyhow::Result<()> {
         self.connection
-            .send(Message::text(render_task))
+            .send(Message::binary(request.to_bytes()))
             .await
-            .context("Failed to send render task")?;
+            .context("Failed to send request")
+    }
 
-        // TODO: Process case when connection was gracefully closed.
-        let image = self
-            .connection
-            .next()
-            .await
-            .unwrap()
-            .context("Failed to receive image")?;
-        let Message::Binary(image) = image else {
-            anyhow::bail!("Unexpected message format");
+    async fn receive(&mut self) -> anyhow::Result<Response> {
+        loop {
+            let message = self
+                .connection
+                .next()
+                .await
+                .context("Connection closed")?
+                .context("Failed to receive response")?;
+            match message {
+                Message::Binary(message) => return Response::from_bytes(&message),
+                Message::Ping(_) | Message::Pong(_) => {}
+                _ => anyhow::bail!("Unexpected message format"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use worker::api::render_task::RenderTaskUninit;
+
+    use super::*;
+
+    fn render_task(width: usize, height: usize, crop: Option<Crop>) -> RenderTask {
+        let render_task: RenderTaskUninit = serde_json::from_str(
+            r#"{
+                "scene": "scene.json",
+                "config": {},
+                "camera": {
+                    "resolution": [0, 0],
+                    "rotation": [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
+                    "position": [0, 0, 0],
+                    "field_of_view": 0.5,
+                    "near_plane": 0.0,
+                    "focal_length": 1.0,
+                    "bokeh_shape": "circle",
+                    "bokeh_size": 0.0
+                }
+            }"#,
+        )
+        .unwrap();
+        let mut render_task = render_task.init("scene_md5".to_string());
+        render_task.camera.resolution = UVec2::new(width, height);
+        render_task.crop = crop;
+        render_task
+    }
+
+    // Number of times every pixel of the frame is rendered by the jobs.
+    fn coverage(width: usize, height: usize, jobs: &[RenderTask]) -> Vec<usize> {
+        let mut coverage = vec![0; width * height];
+        for job in jobs {
+            let crop = job.crop();
+            for y in crop.offset.y..crop.offset.y + crop.size.y {
+                for x in crop.offset.x..crop.offset.x + crop.size.x {
+                    coverage[x + y * width] += 1;
+                }
+            }
+        }
+        coverage
+    }
+
+    #[test]
+    fn tiles_cover_frame_once() {
+        for (width, height) in [(1, 1), (64, 64), (100, 37), (129, 200), (300, 65)] {
+            let tiles = split_into_tiles(&render_task(width, height, None));
+            let tiles: Vec<_> = tiles.into_iter().collect();
+            assert!(
+                coverage(width, height, &tiles)
+                    .iter()
+                    .all(|&count| count == 1)
+            );
+            for tile in &tiles {
+                assert!(tile.crop().size.x <= TILE_SIZE && tile.crop().size.y <= TILE_SIZE);
+            }
+        }
+    }
+
+    #[test]
+    fn tiles_cover_crop_once() {
+        let crop = Crop {
+            offset: UVec2::new(10, 20),
+            size: UVec2::new(150, 70),
         };
+        let tiles: Vec<_> = split_into_tiles(&render_task(200, 100, Some(crop)))
+            .into_iter()
+            .collect();
+        let coverage = coverage(200, 100, &tiles);
+        for y in 0..100 {
+            for x in 0..200 {
+                let in_crop = (10..160).contains(&x) && (20..90).contains(&y);
+                assert_eq!(coverage[x + y * 200], in_crop as usize);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn merged_tiles_cover_frame_once() {
+        for (width, height) in [(100, 37), (129, 200), (300, 65)] {
+            let jobs = JobQueue::default();
+            jobs.push(split_into_tiles(&render_task(width, height, None)))
+                .await;
+
+            let mut merged = vec![];
+            while !jobs.jobs.lock().await.is_empty() {
+                merged.push(jobs.pop(Some(f32::MAX)).await);
+            }
+            assert!(
+                coverage(width, height, &merged)
+                    .iter()
+                    .all(|&count| count == 1)
+            );
+            // Whole rows of tiles are merged with an unlimited budget.
+            assert_eq!(merged.len(), height.div_ceil(TILE_SIZE));
+        }
+    }
 
-        let image = RenderedImage::from_bytes(image.to_vec()).image;
-        frame.add_render(image).await;
+    #[test]
+    fn only_adjacent_tiles_of_same_pass_are_merged() {
+        let tiles = split_into_tiles(&render_task(200, 100, None));
+        assert!(merge_tiles(&tiles[0], &tiles[1]).is_some());
+        assert!(merge_tiles(&tiles[1], &tiles[0]).is_none());
+        // Last tile of the first row and the first one of the second row.
+        assert!(merge_tiles(&tiles[3], &tiles[4]).is_none());
+        assert!(merge_tiles(&tiles[0], &tiles[4]).is_none());
 
-        Ok(())
+        let mut other_pass = tiles[1].clone();
+        other_pass.config.seed += 1;
+        assert!(merge_tiles(&tiles[0], &other_pass).is_none());
     }
 }
@@ -205,7 +205,8 @@ impl Basis {
             Vec3::new(-j.y, j.x, 0.0)
         } else {
             Vec3::new(0.0, j.z, -j.y)
-        };
+        }
+        .normalized();
         let k = j.cross(i);
         Basis { i, j, k }
     }
@@ -248,6 +249,14 @@ impl Vec3 {
         self.x * rhs.x + self.y * rhs.y + self.z * rhs.z
     }
 
+    pub fn min(&self, rhs: Vec3) -> Vec3 {
+        Vec3::new(self.x.min(rhs.x), self.y.min(rhs.y), self.z.min(rhs.z))
+    }
+
+    pub fn max(&self, rhs: Vec3) -> Vec3 {
+        Vec3::new(self.x.max(rhs.x), self.y.max(rhs.y), self.z.max(rhs.z))
+    }
+
     pub fn cross(&self, rhs: Vec3) -> Vec3 {
         Vec3 {
             x: self.y * rhs.z - self.z * rhs.y,
@@ -288,15 +297,26 @@ impl Vec3 {
         }
     }
 
+    // Pdf is cos(theta) / PI.
     pub fn cosine_weighted_random_on_hemisphere(rand_0: f32, rand_1: f32, normal: Vec3) -> Vec3 {
-        let rand_0 = f32::cos(PI * 0.5 * (1.0 - rand_0));
         let theta = rand_1 * PI * 2.0;
 
         let basis = Basis::build_orthonormal_by_j(normal);
         let r = rand_0.sqrt();
         // Without EPSILON ray goes under the surface sometimes.
-        let rand = basis.j * (1.0 - rand_0 + EPSILON);
-        rand + r * (basis.i * f32::cos(theta) + basis.k * f32::sin(theta))
+        let rand = basis.j * ((1.0 - rand_0).sqrt() + EPSILON);
+        (rand + r * (basis.i * f32::cos(theta) + basis.k * f32::sin(theta))).normalized()
+    }
+
+    // Samples GGX microfacet normal, pdf is D(h) * cos(theta).
+    pub fn ggx_random_on_hemisphere(rand_0: f32, rand_1: f32, normal: Vec3, alpha: f32) -> Vec3 {
+        let alpha_sqr = alpha * alpha;
+        let theta_cos = ((1.0 - rand_0) / (rand_0 * (alpha_sqr - 1.0) + 1.0)).sqrt();
+        let theta_sin = (1.0 - theta_cos * theta_cos).max(0.0).sqrt();
+        let phi = rand_1 * PI * 2.0;
+
+        let basis = Basis::build_orthonormal_by_j(normal);
+        basis.j * theta_cos + theta_sin * (basis.i * f32::cos(phi) + basis.k * f32::sin(phi))
     }
 
     // Angle is in steradians.
@@ -317,6 +337,29 @@ impl From<[f32; 3]> for Vec3 {
     }
 }
 
+impl ops::Index<usize> for Vec3 {
+    type Output = f32;
+    fn index(&self, index: usize) -> &f32 {
+        match index {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 index out of range: {}", index),
+        }
+    }
+}
+
+impl ops::IndexMut<usize> for Vec3 {
+    fn index_mut(&mut self, index: usize) -> &mut f32 {
+        match index {
+            0 => &mut self.x,
+            1 => &mut self.y,
+            2 => &mut self.z,
+            _ => panic!("Vec3 index out of range: {}", index),
+        }
+    }
+}
+
 impl ops::Add<Vec3> for Vec3 {
     type Output = Vec3;
     fn add(self, rhs: Vec3) -> Vec3 {
@@ -429,6 +472,14 @@ impl Mat3 {
             + self.row0.z * (self.row1.x * self.row2.y - self.row1.y * self.row2.x)
     }
 
+    pub fn transposed(&self) -> Mat3 {
+        Mat3::new(
+            Vec3::new(self.row0.x, self.row1.x, self.row2.x),
+            Vec3::new(self.row0.y, self.row1.y, self.row2.y),
+            Vec3::new(self.row0.z, self.row1.z, self.row2.z),
+        )
+    }
+
     pub fn transponsed_inverse(&self) -> Mat3 {
         Mat3 {
             row0: Vec3::new(
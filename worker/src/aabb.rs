@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+
+use math::{Mat4, Vec3, Vec4};
+
+use crate::ray::Ray;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Default for Aabb {
+    fn default() -> Aabb {
+        Aabb::empty()
+    }
+}
+
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new_xyz(f32::INFINITY),
+            max: Vec3::new_xyz(f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn infinite() -> Aabb {
+        Aabb {
+            min: Vec3::new_xyz(f32::NEG_INFINITY),
+            max: Vec3::new_xyz(f32::INFINITY),
+        }
+    }
+
+    pub fn from_points(points: &[Vec3]) -> Aabb {
+        points
+            .iter()
+            .fold(Aabb::empty(), |aabb, &point| aabb.extend(point))
+    }
+
+    pub fn extend(&self, point: Vec3) -> Aabb {
+        Aabb::new(self.min.min(point), self.max.max(point))
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb::new(self.min.min(other.min), self.max.max(other.max))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn is_finite(&self) -> bool {
+        [self.min, self.max]
+            .iter()
+            .all(|v| v.x.is_finite() && v.y.is_finite() && v.z.is_finite())
+    }
+
+    pub fn diagonal(&self) -> Vec3 {
+        self.max - self.min
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let d = self.diagonal();
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn max_extent_axis(&self) -> usize {
+        let d = self.diagonal();
+        if d.x > d.y && d.x > d.z {
+            0
+        } else if d.y > d.z {
+            1
+        } else {
+            2
+        }
+    }
+
+    pub fn transform(&self, matrix: &Mat4) -> Aabb {
+        if self.is_empty() || !self.is_finite() {
+            return *self;
+        }
+
+        (0..8)
+            .map(|corner: usize| {
+                let pick = |axis: usize| {
+                    if corner & (1 << axis) == 0 {
+                        self.min[axis]
+                    } else {
+                        self.max[axis]
+                    }
+                };
+                Vec3::new(pick(0), pick(1), pick(2))
+            })
+            .fold(Aabb::empty(), |aabb, corner| {
+                aabb.extend(matrix * Vec4::from_vec3(corner))
+            })
+    }
+
+    // Returns ray parameters of entry and exit points clamped to [ray.min, ray.max].
+    pub fn intersect(&self, ray: &Ray) -> Option<(f32, f32)> {
+        if self.is_empty() {
+            return None;
+        }
+
+        let mut t_min = ray.min;
+        let mut t_max = ray.max;
+
+        for axis in 0..3 {
+            let inv_direction = 1.0 / ray.direction[axis];
+            let mut t_near = (self.min[axis] - ray.source[axis]) * inv_direction;
+            let mut t_far = (self.max[axis] - ray.source[axis]) * inv_direction;
+            if t_near > t_far {
+                std::mem::swap(&mut t_near, &mut t_far);
+            }
+
+            // f32::max/min ignore NaN, produced when the ray lies on a slab's plane.
+            t_min = t_min.max(t_near);
+            t_max = t_max.min(t_far);
+            if t_min > t_max {
+                return None;
+            }
+        }
+
+        Some((t_min, t_max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unit_box() -> Aabb {
+        Aabb::new(Vec3::new_xyz(-1.0), Vec3::new_xyz(1.0))
+    }
+
+    fn components(v: Vec3) -> [f32; 3] {
+        [v.x, v.y, v.z]
+    }
+
+    fn ray(source: Vec3, direction: Vec3) -> Ray {
+        Ray::new(source, direction, 0.0, f32::MAX)
+    }
+
+    #[test]
+    fn axis_parallel_rays() {
+        let aabb = unit_box();
+        let hit = aabb.intersect(&ray(Vec3::new(-5.0, 0.5, 0.5), Vec3::new(1.0, 0.0, 0.0)));
+        assert_eq!(hit, Some((4.0, 6.0)));
+        let hit = aabb.intersect(&ray(Vec3::new(0.5, 0.5, 5.0), Vec3::new(0.0, 0.0, -1.0)));
+        assert_eq!(hit, Some((4.0, 6.0)));
+
+        // Parallel to the slab, but outside of it.
+        let miss = aabb.intersect(&ray(Vec3::new(-5.0, 2.0, 0.0), Vec3::new(1.0, 0.0, 0.0)));
+        assert_eq!(miss, None);
+        // Lying on the slab plane.
+        let hit = aabb.intersect(&ray(Vec3::new(-5.0, 1.0, 0.0), Vec3::new(1.0, 0.0, 0.0)));
+        assert_eq!(hit, Some((4.0, 6.0)));
+        // Pointing away from the box.
+        let miss = aabb.intersect(&ray(Vec3::new(-5.0, 0.0, 0.0), Vec3::new(-1.0, 0.0, 0.0)));
+        assert_eq!(miss, None);
+    }
+
+    #[test]
+    fn rays_starting_inside() {
+        let aabb = unit_box();
+        let hit = aabb.intersect(&ray(Vec3::default(), Vec3::new(0.0, 1.0, 0.0)));
+        assert_eq!(hit, Some((0.0, 1.0)));
+
+        let direction = Vec3::new(1.0, 1.0, 0.0).normalized();
+        let (t_min, t_max) = aabb.intersect(&ray(Vec3::default(), direction)).unwrap();
+        assert_eq!(t_min, 0.0);
+        assert!((t_max - 2.0f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn intersection_is_clamped_to_ray() {
+        let aabb = unit_box();
+        let source = Vec3::new(-5.0, 0.0, 0.0);
+        let direction = Vec3::new(1.0, 0.0, 0.0);
+        let hit = aabb.intersect(&Ray::new(source, direction, 5.0, 5.5));
+        assert_eq!(hit, Some((5.0, 5.5)));
+        let miss = aabb.intersect(&Ray::new(source, direction, 0.0, 3.0));
+        assert_eq!(miss, None);
+    }
+
+    #[test]
+    fn empty_boxes() {
+        let empty = Aabb::empty();
+        assert!(empty.is_empty());
+        assert_eq!(empty.surface_area(), 0.0);
+        assert_eq!(
+            empty.intersect(&ray(Vec3::default(), Vec3::new(1.0, 0.0, 0.0))),
+            None
+        );
+
+        let union = empty.union(&unit_box());
+        assert_eq!(components(union.min), [-1.0; 3]);
+        assert_eq!(components(union.max), [1.0; 3]);
+        assert!(Aabb::from_points(&[]).is_empty());
+    }
+
+    #[test]
+    fn union_and_surface_area() {
+        let aabb = Aabb::new(Vec3::default(), Vec3::new(1.0, 2.0, 3.0));
+        assert_eq!(aabb.surface_area(), 22.0);
+        assert_eq!(aabb.max_extent_axis(), 2);
+
+        let union = aabb.union(&Aabb::new(Vec3::new_xyz(-1.0), Vec3::default()));
+        assert_eq!(components(union.min), [-1.0; 3]);
+        assert_eq!(components(union.max), [1.0, 2.0, 3.0]);
+        assert_eq!(
+            union.surface_area(),
+            2.0 * (2.0 * 3.0 + 3.0 * 4.0 + 4.0 * 2.0)
+        );
+
+        let flat = Aabb::new(Vec3::default(), Vec3::new(1.0, 1.0, 0.0));
+        assert!(!flat.is_empty());
+        assert_eq!(flat.surface_area(), 2.0);
+        assert!(!Aabb::infinite().is_finite());
+    }
+}
@@ -0,0 +1,154 @@
+use std::{fmt::Display, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::render_task::RenderTask;
+use crate::{RenderedImage, discovery::Capabilities};
+
+// Peers with different versions can't talk to each other, it's bumped on every change
+// of the messages below.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+// Messages are sent as binary websocket messages encoded with postcard.
+// The client starts with Hello, the worker answers with its own Hello, or with Error
+// and closes the connection if the versions don't match.
+// Every Render request is answered with any number of Progress responses followed
+// by exactly one Rendered, Cancelled or Error, in order. Workers without a file store
+// send FetchFileMd5 and FetchFile while loading the scene, the client answers them
+// with FileMd5 and File.
+#[derive(Serialize, Deserialize)]
+pub enum Request {
+    Hello {
+        version: u32,
+    },
+    // Replaces the current render if there's one, it's answered with Cancelled.
+    Render {
+        render_task: Box<RenderTask>,
+        // Progress isn't sent if not set.
+        progress: Option<ProgressInterval>,
+    },
+    // Stops the current render between passes, does nothing if there's none.
+    Cancel {
+        keep_partial: bool,
+    },
+    Ping,
+    // Answer to FetchFile, with the reason if the client can't provide the file.
+    File {
+        scene_md5: String,
+        path: String,
+        data: Result<Vec<u8>, String>,
+    },
+    // Answer to FetchFileMd5, files with known md5 are taken from the worker cache.
+    FileMd5 {
+        scene_md5: String,
+        path: String,
+        md5: Result<String, String>,
+    },
+}
+
+// Progress is sent once any of the set limits is reached.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct ProgressInterval {
+    pub iterations: Option<usize>,
+    pub seconds: Option<f32>,
+}
+
+impl ProgressInterval {
+    pub fn is_reached(&self, iterations: usize, elapsed: Duration) -> bool {
+        self.iterations.is_some_and(|limit| iterations >= limit)
+            || self
+                .seconds
+                .is_some_and(|limit| elapsed.as_secs_f32() >= limit)
+    }
+}
+
+// Images hold only samples that weren't sent in previous responses for the same render,
+// so they're combined weighted by their sample counts. Progress already sent is kept
+// even if the render is cancelled without keeping partial results.
+#[derive(Serialize, Deserialize)]
+pub enum Response {
+    Hello {
+        version: u32,
+        capabilities: Capabilities,
+    },
+    Progress(RenderedImage),
+    Rendered(RenderedImage),
+    Cancelled,
+    // Request couldn't be handled, the connection stays open unless it's a failed handshake.
+    Error(WorkerError),
+    Pong,
+    FetchFile {
+        scene_md5: String,
+        path: String,
+    },
+    FetchFileMd5 {
+        scene_md5: String,
+        path: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WorkerError {
+    pub kind: ErrorKind,
+    // Whole chain of causes, e.g. "Failed to load mesh a.obj: Incorrect obj format: ...".
+    pub message: String,
+}
+
+impl WorkerError {
+    pub fn new(kind: ErrorKind, error: &anyhow::Error) -> WorkerError {
+        WorkerError {
+            kind,
+            message: format!("{:#}", error),
+        }
+    }
+}
+
+impl Display for WorkerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    UnsupportedVersion,
+    // Request couldn't be decoded or isn't expected at this point.
+    InvalidRequest,
+    // Scene files couldn't be fetched or parsed.
+    SceneLoading,
+    // Render panicked after the scene was loaded.
+    Render,
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = match self {
+            ErrorKind::UnsupportedVersion => "Unsupported protocol version",
+            ErrorKind::InvalidRequest => "Invalid request",
+            ErrorKind::SceneLoading => "Scene loading failed",
+            ErrorKind::Render => "Render failed",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl Request {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("Failed to encode request")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        postcard::from_bytes(bytes).context("Failed to decode request")
+    }
+}
+
+impl Response {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        postcard::to_stdvec(self).expect("Failed to encode response")
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        postcard::from_bytes(bytes).context("Failed to decode response")
+    }
+}
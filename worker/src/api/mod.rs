@@ -1,15 +1,20 @@
+pub mod message;
 pub mod render_task;
 
 pub mod scene {
     pub use crate::scene::{
+        SceneHierarchyUninit as SceneHierarchy, build_kd_trees,
         resource::{
-            image::Image, material::BoxedMaterial as Material, mesh::MeshUninit as Mesh, Resource,
-            ResourceReferenceUninit as ResourceReference, ResourceType,
+            Resource, ResourceReferenceUninit as ResourceReference, ResourceType, image::Image,
+            kd_tree::KdTree, material::BoxedMaterial as Material, mesh::MeshUninit as Mesh,
         },
-        SceneHierarchyUninit as SceneHierarchy,
     };
 }
 
+pub mod file_store {
+    pub use crate::file_store::{FileStore, GridFsFileStore, LocalFileStore, MemoryFileStore};
+}
+
 pub mod render_store {
-    pub use crate::render_store::RenderStore;
+    pub use crate::render_store::{GridFsRenderStore, LocalRenderStore, RenderStore, StoredRender};
 }
@@ -1,12 +1,16 @@
 use serde::{Deserialize, Serialize};
 
-use crate::camera::Camera;
+use math::UVec2;
+
+use crate::{camera::Camera, sampler::SamplerType};
 
 #[derive(Deserialize, Serialize, Clone)]
 pub struct RenderTaskUninit {
     pub scene: String,
     pub config: Config,
     pub camera: Camera,
+    #[serde(default)]
+    pub crop: Option<Crop>,
 }
 
 impl RenderTaskUninit {
@@ -16,6 +20,7 @@ impl RenderTaskUninit {
             scene_md5,
             config: self.config,
             camera: self.camera,
+            crop: self.crop,
         }
     }
 }
@@ -26,13 +31,44 @@ pub struct RenderTask {
     pub scene_md5: String,
     pub config: Config,
     pub camera: Camera,
+    // Only this part of the image is rendered, whole image if None.
+    pub crop: Option<Crop>,
+}
+
+// Pixel rectangle in image coordinates, origin is the top left corner.
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub struct Crop {
+    pub offset: UVec2,
+    pub size: UVec2,
 }
 
 impl RenderTask {
+    // Crop clamped to the image, the whole image if there's no crop.
+    pub fn crop(&self) -> Crop {
+        let resolution = self.camera.resolution;
+        let Some(crop) = self.crop else {
+            return Crop {
+                offset: UVec2::default(),
+                size: resolution,
+            };
+        };
+
+        let offset = UVec2::new(
+            crop.offset.x.min(resolution.x),
+            crop.offset.y.min(resolution.y),
+        );
+        let size = UVec2::new(
+            crop.size.x.min(resolution.x - offset.x),
+            crop.size.y.min(resolution.y - offset.y),
+        );
+        Crop { offset, size }
+    }
+
     pub fn md5(&self) -> String {
         let mut config = self.config.clone();
-        // RenderTasks are equal even if there's different iteration count in them
+        // RenderTasks are equal even if there's different iteration count, seed or crop in them
         config.iterations = 0;
+        config.seed = 0;
 
         let ser = self.scene.clone()
             + &self.scene_md5
@@ -43,7 +79,28 @@ impl RenderTask {
 }
 
 #[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
 pub struct Config {
+    // Hard cap on path length, paths reaching it are counted and reported.
     pub trace_depth: usize,
     pub iterations: usize,
+    // Paths longer than this are terminated randomly based on their throughput.
+    pub russian_roulette_depth: usize,
+    pub russian_roulette_min_probability: f32,
+    // Renders with the same seed are identical.
+    pub seed: u64,
+    pub sampler: SamplerType,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            trace_depth: 64,
+            iterations: 1,
+            russian_roulette_depth: 3,
+            russian_roulette_min_probability: 0.05,
+            seed: 0,
+            sampler: SamplerType::default(),
+        }
+    }
 }
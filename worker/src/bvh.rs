@@ -0,0 +1,350 @@
+use math::Vec3;
+
+use crate::{aabb::Aabb, ray::Ray, renderer::cpu_renderer::RayTraceResult};
+
+const BIN_COUNT: usize = 16;
+const TRAVERSAL_COST: f32 = 0.125;
+const MAX_LEAF_PRIMITIVES: usize = 4;
+// Leaves bigger than this are split even if SAH says it doesn't pay off.
+const MAX_SAH_LEAF_PRIMITIVES: usize = 16;
+
+enum BvhNodeKind {
+    // First child always directly follows its parent.
+    Interior { second_child: u32, axis: u8 },
+    Leaf { first: u32, count: u32 },
+}
+
+struct BvhNode {
+    bounds: Aabb,
+    kind: BvhNodeKind,
+}
+
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    primitive_ids: Vec<u32>,
+    // Primitives with infinite bounds (e.g. planes) are tested against every ray.
+    unbounded_ids: Vec<u32>,
+}
+
+#[derive(Clone, Copy)]
+struct Bin {
+    bounds: Aabb,
+    count: usize,
+}
+
+impl Bvh {
+    pub fn build(primitive_bounds: &[Aabb]) -> Bvh {
+        let mut bvh = Bvh::default();
+
+        let mut bounded_ids = vec![];
+        for (id, bounds) in primitive_bounds.iter().enumerate() {
+            if bounds.is_empty() {
+                continue;
+            }
+            if bounds.is_finite() {
+                bounded_ids.push(id as u32);
+            } else {
+                bvh.unbounded_ids.push(id as u32);
+            }
+        }
+
+        if !bounded_ids.is_empty() {
+            let centroids: Vec<_> = primitive_bounds.iter().map(Aabb::center).collect();
+            bvh.build_node(primitive_bounds, &centroids, &mut bounded_ids, 0);
+            bvh.primitive_ids = bounded_ids;
+        }
+
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        if !self.unbounded_ids.is_empty() {
+            return Aabb::infinite();
+        }
+        self.nodes
+            .first()
+            .map(|root| root.bounds)
+            .unwrap_or_default()
+    }
+
+    // Ids are partitioned in place, offset is the position of the ids slice in the final order.
+    fn build_node(
+        &mut self,
+        primitive_bounds: &[Aabb],
+        centroids: &[Vec3],
+        ids: &mut [u32],
+        offset: usize,
+    ) {
+        let bounds = ids.iter().fold(Aabb::empty(), |bounds, &id| {
+            bounds.union(&primitive_bounds[id as usize])
+        });
+        let leaf = BvhNode {
+            bounds,
+            kind: BvhNodeKind::Leaf {
+                first: offset as u32,
+                count: ids.len() as u32,
+            },
+        };
+
+        if ids.len() <= MAX_LEAF_PRIMITIVES {
+            self.nodes.push(leaf);
+            return;
+        }
+
+        let centroid_bounds = ids.iter().fold(Aabb::empty(), |bounds, &id| {
+            bounds.extend(centroids[id as usize])
+        });
+        let axis = centroid_bounds.max_extent_axis();
+        let extent = centroid_bounds.diagonal()[axis];
+
+        let split = if extent > 0.0 {
+            let bin_id = |id: u32| {
+                let relative = (centroids[id as usize][axis] - centroid_bounds.min[axis]) / extent;
+                ((relative * BIN_COUNT as f32) as usize).min(BIN_COUNT - 1)
+            };
+
+            let mut bins = [Bin {
+                bounds: Aabb::empty(),
+                count: 0,
+            }; BIN_COUNT];
+            for &id in ids.iter() {
+                let bin = &mut bins[bin_id(id)];
+                bin.bounds = bin.bounds.union(&primitive_bounds[id as usize]);
+                bin.count += 1;
+            }
+
+            let inv_area = 1.0 / bounds.surface_area();
+            let mut best: Option<(f32, usize)> = None;
+            for split_bin in 1..BIN_COUNT {
+                let side_cost = |bins: &[Bin]| {
+                    let (bounds, count) = bins.iter().fold((Aabb::empty(), 0), |acc, bin| {
+                        (acc.0.union(&bin.bounds), acc.1 + bin.count)
+                    });
+                    bounds.surface_area() * count as f32
+                };
+                let cost = TRAVERSAL_COST
+                    + (side_cost(&bins[..split_bin]) + side_cost(&bins[split_bin..])) * inv_area;
+                if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                    best = Some((cost, split_bin));
+                }
+            }
+
+            match best {
+                Some((cost, _))
+                    if cost >= ids.len() as f32 && ids.len() <= MAX_SAH_LEAF_PRIMITIVES =>
+                {
+                    self.nodes.push(leaf);
+                    return;
+                }
+                Some((_, split_bin)) => {
+                    let mut split = 0;
+                    for i in 0..ids.len() {
+                        if bin_id(ids[i]) < split_bin {
+                            ids.swap(i, split);
+                            split += 1;
+                        }
+                    }
+                    split
+                }
+                None => 0,
+            }
+        } else {
+            if ids.len() <= MAX_SAH_LEAF_PRIMITIVES {
+                self.nodes.push(leaf);
+                return;
+            }
+            0
+        };
+
+        // Fall back to the median split when binning fails to separate primitives.
+        let split = if split == 0 || split == ids.len() {
+            let median = ids.len() / 2;
+            ids.select_nth_unstable_by(median, |&a, &b| {
+                centroids[a as usize][axis].total_cmp(&centroids[b as usize][axis])
+            });
+            median
+        } else {
+            split
+        };
+
+        let node_id = self.nodes.len();
+        self.nodes.push(leaf);
+
+        let (first_ids, second_ids) = ids.split_at_mut(split);
+        self.build_node(primitive_bounds, centroids, first_ids, offset);
+        let second_child = self.nodes.len() as u32;
+        self.build_node(primitive_bounds, centroids, second_ids, offset + split);
+
+        self.nodes[node_id].kind = BvhNodeKind::Interior {
+            second_child,
+            axis: axis as u8,
+        };
+    }
+
+    pub fn trace_ray(
+        &self,
+        ray: &Ray,
+        mut trace_primitive: impl FnMut(usize, &Ray) -> RayTraceResult,
+    ) -> RayTraceResult {
+        let mut result = RayTraceResult::void();
+        let mut ray = Ray::new(ray.source, ray.direction, ray.min, ray.max);
+
+        let mut test_primitive = |id: u32, ray: &mut Ray, result: &mut RayTraceResult| {
+            let primitive_result = trace_primitive(id as usize, ray);
+            if primitive_result.hit && primitive_result.t <= ray.max {
+                ray.max = primitive_result.t;
+                *result = primitive_result;
+            }
+        };
+
+        for &id in &self.unbounded_ids {
+            test_primitive(id, &mut ray, &mut result);
+        }
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let direction_is_negative = [
+            ray.direction.x < 0.0,
+            ray.direction.y < 0.0,
+            ray.direction.z < 0.0,
+        ];
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+        while let Some(node_id) = stack.pop() {
+            let node = &self.nodes[node_id];
+            // Ray max shrinks with every hit, so farther nodes get culled here.
+            if node.bounds.intersect(&ray).is_none() {
+                continue;
+            }
+
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    let first = first as usize;
+                    for &id in &self.primitive_ids[first..first + count as usize] {
+                        test_primitive(id, &mut ray, &mut result);
+                    }
+                }
+                BvhNodeKind::Interior { second_child, axis } => {
+                    // Nearer child is pushed last to be visited first.
+                    if direction_is_negative[axis as usize] {
+                        stack.push(node_id + 1);
+                        stack.push(second_child as usize);
+                    } else {
+                        stack.push(second_child as usize);
+                        stack.push(node_id + 1);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn random_vec3(rng: &mut StdRng, min: f32, max: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+        )
+    }
+
+    fn random_boxes(rng: &mut StdRng, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let min = random_vec3(rng, -10.0, 10.0);
+                Aabb::new(min, min + random_vec3(rng, 0.0, 2.0))
+            })
+            .collect()
+    }
+
+    fn random_rays(rng: &mut StdRng) -> Vec<Ray> {
+        (0..1000)
+            .map(|_| {
+                let direction = random_vec3(rng, -1.0, 1.0).normalized();
+                Ray::new(random_vec3(rng, -15.0, 15.0), direction, 0.0, f32::MAX)
+            })
+            .collect()
+    }
+
+    fn assert_matches_brute_force(boxes: &[Aabb]) {
+        let bvh = Bvh::build(boxes);
+        for ray in random_rays(&mut StdRng::seed_from_u64(0)) {
+            let result = bvh.trace_ray(&ray, |id, ray| match boxes[id].intersect(ray) {
+                Some((t, _)) => RayTraceResult {
+                    hit: true,
+                    t,
+                    ..RayTraceResult::void()
+                },
+                None => RayTraceResult::void(),
+            });
+            let expected = boxes
+                .iter()
+                .filter_map(|bounds| bounds.intersect(&ray))
+                .map(|(t, _)| t)
+                .min_by(f32::total_cmp);
+            assert_eq!(result.hit.then_some(result.t), expected);
+        }
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for count in [10, 100, 1000] {
+            assert_matches_brute_force(&random_boxes(&mut rng, count));
+        }
+    }
+
+    #[test]
+    fn single_primitive() {
+        let boxes = [Aabb::new(Vec3::new_xyz(-1.0), Vec3::new_xyz(1.0))];
+        assert_matches_brute_force(&boxes);
+        assert_eq!(Bvh::build(&boxes).nodes.len(), 1);
+    }
+
+    // Binning can't separate primitives with the same centroid, so they're split by the median.
+    #[test]
+    fn equal_centroids() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let boxes: Vec<_> = (0..100)
+            .map(|_| {
+                let size = random_vec3(&mut rng, 0.1, 5.0);
+                Aabb::new(size * -1.0, size)
+            })
+            .collect();
+        assert_matches_brute_force(&boxes);
+
+        let bvh = Bvh::build(&boxes);
+        let max_leaf = bvh
+            .nodes
+            .iter()
+            .filter_map(|node| match node.kind {
+                BvhNodeKind::Leaf { count, .. } => Some(count as usize),
+                BvhNodeKind::Interior { .. } => None,
+            })
+            .max();
+        assert!(max_leaf.unwrap() <= MAX_SAH_LEAF_PRIMITIVES);
+    }
+
+    #[test]
+    fn unbounded_and_empty_primitives() {
+        let mut boxes = random_boxes(&mut StdRng::seed_from_u64(3), 50);
+        boxes.push(Aabb::empty());
+        assert_matches_brute_force(&boxes);
+
+        boxes.push(Aabb::infinite());
+        let bvh = Bvh::build(&boxes);
+        assert!(!bvh.bounds().is_finite());
+        assert_eq!(bvh.unbounded_ids, [boxes.len() as u32 - 1]);
+    }
+}
@@ -1,9 +1,8 @@
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 
 use math::{Mat3, UVec2, Vec2, Vec3};
 
-use crate::ray::Ray;
+use crate::{ray::Ray, sampler::Sampler};
 
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -14,20 +13,17 @@ pub enum BokehShape {
 }
 
 impl BokehShape {
-    fn sample(&self) -> Vec2 {
+    fn sample(&self, sampler: &mut dyn Sampler) -> Vec2 {
+        let rand = sampler.get_2d();
         match self {
             BokehShape::Point => Vec2::new(0.0, 0.0),
             BokehShape::Circle => {
-                let mut rng = rand::prelude::thread_rng();
-                let phi = rng.gen_range(0.0..2.0 * math::PI);
-                let r = f32::sqrt(rng.gen_range(0.0..1.0));
+                let phi = rand.x * 2.0 * math::PI;
+                let r = f32::sqrt(rand.y);
                 let phi_sin_cos = f32::sin_cos(phi);
                 r * Vec2::new(phi_sin_cos.1, phi_sin_cos.0)
             }
-            BokehShape::Square => {
-                let mut rng = rand::prelude::thread_rng();
-                Vec2::new(rng.gen_range(-0.5..0.5), rng.gen_range(-0.5..0.5))
-            }
+            BokehShape::Square => Vec2::new(rand.x - 0.5, rand.y - 0.5),
         }
     }
 }
@@ -48,10 +44,10 @@ pub struct Camera {
 }
 
 impl Camera {
-    pub fn get_ray(&self, point: UVec2) -> Ray {
-        let mut rng = rand::prelude::thread_rng();
-        let x_offset = rng.gen_range(-0.5..0.5);
-        let y_offset = rng.gen_range(-0.5..0.5);
+    pub fn get_ray(&self, point: UVec2, sampler: &mut dyn Sampler) -> Ray {
+        let offset = sampler.get_2d();
+        let x_offset = offset.x - 0.5;
+        let y_offset = offset.y - 0.5;
 
         let mut viewport = Vec2::new(self.focal_length * f32::tan(self.fov * 0.5) * 2.0, 0.0);
         viewport.y = viewport.x * (self.resolution.y as f32 / self.resolution.x as f32);
@@ -66,7 +62,7 @@ impl Camera {
 
         let mut point_on_objective = self.position;
 
-        let objective_sample = self.bokeh_shape.sample() * self.bokeh_size;
+        let objective_sample = self.bokeh_shape.sample(sampler) * self.bokeh_size;
         point_on_objective.x += objective_sample.x;
         point_on_objective.y += objective_sample.y;
 
@@ -0,0 +1,73 @@
+use anyhow::Context;
+use futures_util::io::AsyncReadExt;
+use mongodb::{
+    Client, Database, GridFsBucket,
+    bson::{Document, doc},
+    options::{ClientOptions, GridFsBucketOptions},
+};
+
+use super::FileStore;
+
+// Contents of scene files are kept once in the "files" bucket, named by their md5.
+// Collection named by the scene md5 maps paths of its files to their md5.
+pub struct GridFsFileStore {
+    database: Database,
+}
+
+impl GridFsFileStore {
+    pub async fn connect(mongodb_url: &str) -> anyhow::Result<GridFsFileStore> {
+        let client_options = ClientOptions::parse(mongodb_url)
+            .await
+            .context("Incorrect MongoDB url")?;
+        let client =
+            Client::with_options(client_options).context("Failed to connect to MongoDB")?;
+
+        let database = client.database("scene_files");
+
+        Ok(GridFsFileStore { database })
+    }
+
+    fn bucket(&self) -> GridFsBucket {
+        self.database.gridfs_bucket(Some(
+            GridFsBucketOptions::builder()
+                .bucket_name("files".to_string())
+                .build(),
+        ))
+    }
+
+    async fn find_md5(&self, scene_md5: &str, path: &str) -> anyhow::Result<String> {
+        let file = self
+            .database
+            .collection::<Document>(scene_md5)
+            .find_one(doc! { "path": path }, None)
+            .await
+            .with_context(|| format!("Failed to fetch file {}", path))?
+            .with_context(|| format!("File {} not found", path))?;
+        let md5 = file
+            .get_str("md5")
+            .with_context(|| format!("Extraneous file in database: No md5 of file {}", path))?;
+        Ok(md5.to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl FileStore for GridFsFileStore {
+    async fn fetch_file(&self, scene_md5: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+        let md5 = self.find_md5(scene_md5, path).await?;
+        let mut stream = self
+            .bucket()
+            .open_download_stream_by_name(md5, None)
+            .await
+            .with_context(|| format!("Failed to fetch file {}", path))?;
+        let mut file_data = vec![];
+        stream
+            .read_to_end(&mut file_data)
+            .await
+            .with_context(|| format!("Failed to read file {}", path))?;
+        Ok(file_data)
+    }
+
+    async fn file_md5(&self, scene_md5: &str, path: &str) -> anyhow::Result<Option<String>> {
+        self.find_md5(scene_md5, path).await.map(Some)
+    }
+}
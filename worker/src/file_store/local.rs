@@ -0,0 +1,26 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use super::{FileStore, normalize_path};
+
+// Serves files of a single scene from a directory, scene md5 is ignored.
+pub struct LocalFileStore {
+    root: PathBuf,
+}
+
+impl LocalFileStore {
+    pub fn new(root: PathBuf) -> LocalFileStore {
+        LocalFileStore { root }
+    }
+}
+
+#[async_trait::async_trait]
+impl FileStore for LocalFileStore {
+    async fn fetch_file(&self, _scene_md5: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+        let absolute_path = self.root.join(normalize_path(path)?);
+        tokio::fs::read(&absolute_path)
+            .await
+            .with_context(|| format!("Failed to read file {}", absolute_path.display()))
+    }
+}
@@ -0,0 +1,108 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::Context;
+
+use super::{FileStore, normalize_path};
+
+// Keeps files of a single scene in memory, scene md5 is ignored.
+#[derive(Default)]
+pub struct MemoryFileStore {
+    files: HashMap<String, Vec<u8>>,
+}
+
+impl MemoryFileStore {
+    pub fn new() -> MemoryFileStore {
+        MemoryFileStore::default()
+    }
+
+    // Reads all the files under the directory.
+    pub fn from_dir(root: &Path) -> anyhow::Result<MemoryFileStore> {
+        let mut file_store = MemoryFileStore::new();
+        let mut pending_dirs = vec![root.to_path_buf()];
+        while let Some(dir) = pending_dirs.pop() {
+            let entries = std::fs::read_dir(&dir)
+                .with_context(|| format!("Failed to read directory {}", dir.display()))?;
+            for entry in entries {
+                let path = entry?.path();
+                if path.is_dir() {
+                    pending_dirs.push(path);
+                    continue;
+                }
+
+                let data = std::fs::read(&path)
+                    .with_context(|| format!("Failed to read file {}", path.display()))?;
+                let relative_path = path
+                    .strip_prefix(root)?
+                    .to_str()
+                    .with_context(|| format!("Path {} isn't valid UTF-8", path.display()))?;
+                file_store.insert(relative_path, data)?;
+            }
+        }
+        Ok(file_store)
+    }
+
+    pub fn insert(&mut self, path: &str, data: Vec<u8>) -> anyhow::Result<()> {
+        self.files.insert(normalize_path(path)?, data);
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl FileStore for MemoryFileStore {
+    async fn fetch_file(&self, _scene_md5: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+        self.files
+            .get(&normalize_path(path)?)
+            .cloned()
+            .with_context(|| format!("File {} not found", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, atomic::AtomicBool};
+
+    use super::*;
+    use crate::{Worker, api::render_task::RenderTaskUninit};
+
+    #[tokio::test]
+    async fn renders_scene_from_memory() {
+        let mut file_store = MemoryFileStore::new();
+        let scene = r#"{"type":"sphere","center":[0,0,0],"radius":1.0,"material":"./light.json"}"#;
+        file_store.insert("scene.json", scene.into()).unwrap();
+        let material = r#"{"type":"base","emissive":1.0,"emission":[1,1,1]}"#;
+        file_store.insert("light.json", material.into()).unwrap();
+
+        let render_task: RenderTaskUninit = serde_json::from_str(
+            r#"{
+                "scene": "scene.json",
+                "config": { "iterations": 2 },
+                "camera": {
+                    "resolution": [8, 8],
+                    "rotation": [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
+                    "position": [0, 0, 5],
+                    "field_of_view": 0.2,
+                    "near_plane": 0.0,
+                    "focal_length": 5.0,
+                    "bokeh_shape": "circle",
+                    "bokeh_size": 0.0
+                },
+                "crop": { "offset": [3, 3], "size": [2, 2] }
+            }"#,
+        )
+        .unwrap();
+        let render_task = render_task.init("scene_md5".to_string());
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let rendered = Worker::new(None)
+            .render(render_task, &file_store, cancelled, None, |_| async {})
+            .await
+            .unwrap();
+
+        assert_eq!(rendered.offset, (3, 3));
+        assert_eq!(rendered.sample_count, 2);
+        assert_eq!(rendered.image.dimensions(), (2, 2));
+        for pixel in rendered.image.pixels() {
+            assert_eq!(pixel.0, [1.0, 1.0, 1.0]);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use std::path::{Component, Path};
+
+mod grid_fs;
+mod local;
+mod memory;
+
+pub use grid_fs::GridFsFileStore;
+pub use local::LocalFileStore;
+pub use memory::MemoryFileStore;
+
+// Source of scene files. Paths are relative to the scene root.
+#[async_trait::async_trait]
+pub trait FileStore: Send + Sync {
+    async fn fetch_file(&self, scene_md5: &str, path: &str) -> anyhow::Result<Vec<u8>>;
+
+    // Lets cached files be used without fetching them. None if the store can't tell
+    // it without reading the file.
+    async fn file_md5(&self, _scene_md5: &str, _path: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+// Key of the file in the store, e.g. "./meshes/a.obj" becomes "meshes/a.obj".
+// Paths leaving the scene root are rejected.
+fn normalize_path(path: &str) -> anyhow::Result<String> {
+    let mut parts = vec![];
+    for component in Path::new(path).components() {
+        match component {
+            Component::Normal(part) => parts.push(
+                part.to_str()
+                    .ok_or_else(|| anyhow::anyhow!("Path {} isn't valid UTF-8", path))?,
+            ),
+            Component::CurDir => {}
+            _ => anyhow::bail!("Path {} points outside of the scene", path),
+        }
+    }
+    Ok(parts.join("/"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_drops_current_dir() {
+        assert_eq!(normalize_path("./a/b").unwrap(), "a/b");
+        assert_eq!(normalize_path("a/./b").unwrap(), "a/b");
+    }
+
+    #[test]
+    fn normalize_path_rejects_paths_outside_of_scene() {
+        assert!(normalize_path("../a").is_err());
+        assert!(normalize_path("a/../../b").is_err());
+        assert!(normalize_path("/a/b").is_err());
+    }
+}
@@ -1,83 +1,193 @@
-use std::{collections::HashMap, iter, sync::Arc};
+use std::{
+    borrow::Cow,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Instant,
+};
 
 use file_store::FileStore;
 use image::Rgb32FImage;
 use renderer::{Renderer, cpu_renderer::CPURenderer};
+use resource_cache::ResourceCache;
 use scene::Scene;
+use serde::{Deserialize, Deserializer, Serialize, Serializer, de::Error};
 
+mod aabb;
 pub mod api;
+mod bvh;
 mod camera;
 mod file_store;
 mod ray;
 mod render_store;
 mod renderer;
+mod resource_cache;
+mod sampler;
 mod scene;
 
-use api::render_task::RenderTask;
+use api::{
+    message::{ErrorKind, ProgressInterval, WorkerError},
+    render_task::RenderTask,
+};
+
+// Older scenes are evicted along with the files used only by them.
+const MAX_CACHED_SCENES: usize = 2;
 
 pub struct Worker {
-    mongodb_url: String,
-    cached_scenes: HashMap<String, Arc<Scene>>,
+    // The most recently used scene is the last.
+    cached_scenes: Vec<(String, Arc<Scene>)>,
+    resource_cache: ResourceCache,
 }
 
 impl Worker {
-    pub fn new(mongodb_url: String) -> Self {
+    // Fetched scene files are also kept in the cache directory if it's set.
+    pub fn new(cache_dir: Option<PathBuf>) -> Self {
         Self {
-            mongodb_url,
             cached_scenes: Default::default(),
+            resource_cache: ResourceCache::new(cache_dir),
         }
     }
 
-    pub async fn render(&mut self, render_task: RenderTask) -> Rgb32FImage {
-        if !self.cached_scenes.contains_key(&render_task.scene_md5) {
+    // Stops between iterations once cancelled is set. Returns samples that weren't
+    // passed to on_progress, or the error if the scene couldn't be loaded.
+    // Scene files are fetched from the file store unless the scene is cached.
+    pub async fn render<F: Future<Output = ()>>(
+        &mut self,
+        render_task: RenderTask,
+        file_store: &dyn FileStore,
+        cancelled: Arc<AtomicBool>,
+        progress: Option<ProgressInterval>,
+        mut on_progress: impl FnMut(RenderedImage) -> F,
+    ) -> Result<RenderedImage, WorkerError> {
+        let cached = self
+            .cached_scenes
+            .iter()
+            .position(|(scene_md5, _)| *scene_md5 == render_task.scene_md5);
+        if let Some(position) = cached {
+            println!("Scene files found locally");
+            let scene = self.cached_scenes.remove(position);
+            self.cached_scenes.push(scene);
+        } else {
             println!("Loading scene files...");
-            let file_store = FileStore::connect(&self.mongodb_url, &render_task.scene_md5).await;
-            let scene = Scene::load(&file_store, &render_task.scene).await;
+            let scene = Scene::load(
+                file_store,
+                &self.resource_cache,
+                &render_task.scene_md5,
+                &render_task.scene,
+            )
+            .await
+            .map_err(|err| WorkerError::new(ErrorKind::SceneLoading, &err))?;
             self.cached_scenes
-                .insert(render_task.scene_md5.clone(), Arc::from(scene));
+                .push((render_task.scene_md5.clone(), Arc::from(scene)));
             println!("Scene files loaded");
-        } else {
-            println!("Scene files found locally");
+
+            if self.cached_scenes.len() > MAX_CACHED_SCENES {
+                self.cached_scenes.remove(0);
+            }
+            let scene_md5s: Vec<_> = self
+                .cached_scenes
+                .iter()
+                .map(|(scene_md5, _)| scene_md5.as_str())
+                .collect();
+            self.resource_cache.retain_scenes(&scene_md5s);
         }
 
-        let scene = self.cached_scenes[&render_task.scene_md5].clone();
+        let scene = self.cached_scenes.last().unwrap().1.clone();
         let mut renderer = CPURenderer::init(scene);
         let render_task = Arc::from(render_task);
 
-        renderer.render(render_task).await
+        renderer.start(render_task.clone());
+
+        let mut last_progress = (0, Instant::now());
+        for iteration in 1..=render_task.config.iterations {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+            // Iterations take long, so they don't block the async runtime.
+            renderer = tokio::task::spawn_blocking(move || {
+                renderer.iteration();
+                renderer
+            })
+            .await
+            .map_err(|err| WorkerError::new(ErrorKind::Render, &err.into()))?;
+
+            let is_last = iteration == render_task.config.iterations;
+            if let Some(progress) = &progress
+                && !is_last
+                && progress.is_reached(iteration - last_progress.0, last_progress.1.elapsed())
+            {
+                on_progress(Self::take_image(&mut renderer, &render_task)).await;
+                last_progress = (iteration, Instant::now());
+            }
+        }
+
+        Ok(Self::take_image(&mut renderer, &render_task))
+    }
+
+    fn take_image(renderer: &mut CPURenderer, render_task: &RenderTask) -> RenderedImage {
+        let (image, sample_count, capped_paths) = renderer.take_image();
+        let offset = render_task.crop().offset;
+        RenderedImage {
+            image,
+            offset: (offset.x as u32, offset.y as u32),
+            sample_count: sample_count as u32,
+            capped_paths: capped_paths as u64,
+        }
     }
 }
 
 pub struct RenderedImage {
     pub image: Rgb32FImage,
+    // Position of the image top left corner in the full frame.
+    pub offset: (u32, u32),
+    // Samples per pixel the image is averaged from.
+    pub sample_count: u32,
+    // Paths of the samples that reached the trace depth.
+    pub capped_paths: u64,
 }
 
-impl RenderedImage {
-    pub fn to_bytes(self) -> Vec<u8> {
-        iter::once(self.image.width().to_le_bytes())
-            .chain(iter::once(self.image.height().to_le_bytes()))
-            .chain(self.image.iter().map(|value| value.to_le_bytes()))
-            .flatten()
-            .collect()
+// Serialized through RenderedImageData, as images aren't serializable.
+impl Serialize for RenderedImage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        RenderedImageData {
+            width: self.image.width(),
+            height: self.image.height(),
+            offset: self.offset,
+            sample_count: self.sample_count,
+            capped_paths: self.capped_paths,
+            data: Cow::Borrowed(self.image.as_raw()),
+        }
+        .serialize(serializer)
     }
+}
 
-    pub fn from_bytes(mut bytes: Vec<u8>) -> Self {
-        let width = u32::from_le_bytes(bytes[..4].try_into().unwrap());
-        let height = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
-
-        bytes.drain(..8);
-
-        let data = bytes
-            .chunks_exact(4)
-            .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
-            .collect();
-
-        let image = Rgb32FImage::from_vec(width, height, data).unwrap();
-
-        Self { image }
+impl<'de> Deserialize<'de> for RenderedImage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = RenderedImageData::deserialize(deserializer)?;
+        let image = Rgb32FImage::from_vec(data.width, data.height, data.data.into_owned())
+            .ok_or_else(|| D::Error::custom("Image data doesn't match its size"))?;
+
+        Ok(Self {
+            image,
+            offset: data.offset,
+            sample_count: data.sample_count,
+            capped_paths: data.capped_paths,
+        })
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct RenderedImageData<'a> {
+    width: u32,
+    height: u32,
+    offset: (u32, u32),
+    sample_count: u32,
+    capped_paths: u64,
+    data: Cow<'a, [f32]>,
+}
+
 pub mod discovery {
     use serde::{Deserialize, Serialize};
 
@@ -87,5 +197,23 @@ pub mod discovery {
     #[derive(Serialize, Deserialize, Debug)]
     pub struct Response {
         pub websocket_port: u16,
+        pub capabilities: Capabilities,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone)]
+    pub struct Capabilities {
+        pub thread_count: usize,
+        pub renderer: String,
+        pub version: String,
+    }
+
+    impl Capabilities {
+        pub fn current() -> Capabilities {
+            Capabilities {
+                thread_count: num_cpus::get(),
+                renderer: "cpu".to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+            }
+        }
     }
 }
@@ -1,39 +1,129 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
 use futures::{
     SinkExt, StreamExt,
     stream::{SplitSink, SplitStream},
 };
 use tokio::{
     net::{TcpListener, TcpStream, UdpSocket},
-    sync::Mutex,
+    sync::{Mutex, OwnedMutexGuard, oneshot},
+    task::JoinHandle,
+};
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::protocol::{Message, WebSocketConfig},
 };
-use tokio_tungstenite::{WebSocketStream, tungstenite::protocol::Message};
 
-use worker::{RenderedImage, Worker, api::render_task::RenderTask};
+use worker::{
+    Worker,
+    api::{
+        file_store::{FileStore, GridFsFileStore, LocalFileStore, MemoryFileStore},
+        message::{ErrorKind, PROTOCOL_VERSION, ProgressInterval, Request, Response, WorkerError},
+        render_task::RenderTask,
+    },
+    discovery::Capabilities,
+};
 
 const WEBSOCKET_PORT: u16 = 30000;
 const BROADCAST_PORT: u16 = 40000;
 
 type WsStream = WebSocketStream<TcpStream>;
+type Outgoing = Arc<Mutex<SplitSink<WsStream, Message>>>;
 
 #[derive(Parser)]
 pub struct Cli {
+    // Grid-fs if the MongoDB url is set, client otherwise.
+    #[clap(long, value_enum)]
+    file_store: Option<FileStoreType>,
+    #[clap(long, required_if_eq("file_store", "grid-fs"))]
+    mongodb_url: Option<String>,
+    // Fetched scene files are also kept there, so they aren't fetched again after restart.
     #[clap(long)]
-    mongodb_url: String,
+    cache_dir: Option<PathBuf>,
+    // Root of the scene files for the local and memory file stores.
+    #[clap(long, required_if_eq_any([("file_store", "local"), ("file_store", "memory")]))]
+    scene_dir: Option<PathBuf>,
+}
+
+impl Cli {
+    fn file_store(&self) -> FileStoreType {
+        match (self.file_store, &self.mongodb_url) {
+            (Some(file_store), _) => file_store,
+            (None, Some(_)) => FileStoreType::GridFs,
+            (None, None) => FileStoreType::Client,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq)]
+enum FileStoreType {
+    // Requests files from the client that sent the render.
+    Client,
+    GridFs,
+    // Reads files from the scene directory on demand.
+    Local,
+    // Reads all the files from the scene directory on start.
+    Memory,
 }
 
 #[tokio::main]
 async fn main() {
     let args = Cli::parse();
+    if args.mongodb_url.is_some() && args.file_store() != FileStoreType::GridFs {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "--mongodb-url can only be used with the grid-fs file store",
+            )
+            .exit();
+    }
 
-    let worker = Arc::from(Mutex::new(Worker::new(args.mongodb_url)));
-    start_ws(worker).await;
+    let file_store = create_file_store(&args)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to create file store: {:#}", err));
+    let worker = Arc::from(Mutex::new(Worker::new(args.cache_dir.clone())));
+    start_ws(worker, file_store).await;
 }
 
-async fn start_ws(worker: Arc<Mutex<Worker>>) {
+// Returns None if files are requested from clients.
+async fn create_file_store(args: &Cli) -> anyhow::Result<Option<Arc<dyn FileStore>>> {
+    Ok(Some(match args.file_store() {
+        FileStoreType::Client => return Ok(None),
+        FileStoreType::GridFs => {
+            let mongodb_url = args
+                .mongodb_url
+                .as_ref()
+                .context("MongoDB url is required")?;
+            Arc::new(GridFsFileStore::connect(mongodb_url).await?)
+        }
+        FileStoreType::Local => {
+            let scene_dir = args
+                .scene_dir
+                .clone()
+                .context("Scene directory is required")?;
+            Arc::new(LocalFileStore::new(scene_dir))
+        }
+        FileStoreType::Memory => {
+            let scene_dir = args
+                .scene_dir
+                .as_ref()
+                .context("Scene directory is required")?;
+            Arc::new(MemoryFileStore::from_dir(scene_dir)?)
+        }
+    }))
+}
+
+async fn start_ws(worker: Arc<Mutex<Worker>>, file_store: Option<Arc<dyn FileStore>>) {
     let addr = format!("0.0.0.0:{}", WEBSOCKET_PORT);
 
     let try_socket = TcpListener::bind(&addr).await;
@@ -43,51 +133,415 @@ async fn start_ws(worker: Arc<Mutex<Worker>>) {
     tokio::spawn(listen_discovery_broadcasts());
 
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, addr, worker.clone()));
+        tokio::spawn(handle_connection(
+            stream,
+            addr,
+            worker.clone(),
+            file_store.clone(),
+        ));
     }
 }
 
-async fn handle_connection(raw_stream: TcpStream, addr: SocketAddr, worker: Arc<Mutex<Worker>>) {
+async fn handle_connection(
+    raw_stream: TcpStream,
+    addr: SocketAddr,
+    worker: Arc<Mutex<Worker>>,
+    file_store: Option<Arc<dyn FileStore>>,
+) {
     println!("Incoming TCP connection from: {}", addr);
 
-    let ws_stream = tokio_tungstenite::accept_async(raw_stream)
+    // Scene files sent by the client can exceed the default limits.
+    let config = WebSocketConfig::default()
+        .max_message_size(None)
+        .max_frame_size(None);
+    let ws_stream = tokio_tungstenite::accept_async_with_config(raw_stream, Some(config))
         .await
         .expect("Error during the websocket handshake occurred");
     println!("WebSocket connection established: {}", addr);
 
-    let (mut outgoing, mut incoming) = ws_stream.split();
+    let (outgoing, mut incoming) = ws_stream.split();
+    let outgoing = Arc::new(Mutex::new(outgoing));
+
+    if let Err(err) = handshake(&outgoing, &mut incoming).await {
+        println!("Rejected client {}: {:#}", addr, err);
+        let _ = outgoing.lock().await.close().await;
+        return;
+    }
+
+    let client_files = Arc::new(ClientFileStore::new(outgoing.clone()));
+    let file_store = file_store.unwrap_or_else(|| client_files.clone());
 
+    let mut current_render = None;
     loop {
-        if let Err(err) = connection_loop(&mut outgoing, &mut incoming, worker.clone()).await {
+        if let Err(err) = connection_loop(
+            outgoing.clone(),
+            &mut incoming,
+            worker.clone(),
+            file_store.clone(),
+            &client_files,
+            &mut current_render,
+        )
+        .await
+        {
             println!("Error during message exchange: {}", err);
             break;
         }
     }
+
+    client_files.close();
+    if let Some(render) = current_render {
+        render.cancel(false);
+    }
 }
 
-async fn connection_loop(
-    outgoing: &mut SplitSink<WsStream, Message>,
+// Rejected clients are sent the reason before the error is returned.
+async fn handshake(
+    outgoing: &Outgoing,
     incoming: &mut SplitStream<WsStream>,
-    worker: Arc<Mutex<Worker>>,
 ) -> anyhow::Result<()> {
-    // TODO: Process case when connection was gracefully closed.
-    let message = incoming.next().await.unwrap()?;
-
-    let Message::Text(message) = message else {
-        anyhow::bail!("Unexpected message format");
+    let message = incoming
+        .next()
+        .await
+        .context("Connection closed")?
+        .context("Failed to receive message")?;
+    let request = match message {
+        Message::Binary(message) => Request::from_bytes(&message),
+        _ => Err(anyhow::anyhow!("Unexpected message format")),
     };
-    let render_task: RenderTask = serde_json::from_str(&message)
-        .map_err(|err| anyhow::anyhow!("Failed to decode render task: {}", err))?;
 
-    let image = worker.lock().await.render(render_task).await;
-
-    let image_data = RenderedImage { image }.to_bytes();
-    let message = Message::binary(image_data);
+    let error = match request {
+        Ok(Request::Hello { version }) if version == PROTOCOL_VERSION => {
+            let capabilities = Capabilities::current();
+            return send(
+                outgoing,
+                Response::Hello {
+                    version,
+                    capabilities,
+                },
+            )
+            .await;
+        }
+        Ok(Request::Hello { version }) => WorkerError {
+            kind: ErrorKind::UnsupportedVersion,
+            message: format!(
+                "Client uses version {}, worker uses {}",
+                version, PROTOCOL_VERSION
+            ),
+        },
+        Ok(_) => WorkerError {
+            kind: ErrorKind::InvalidRequest,
+            message: "Expected hello".to_string(),
+        },
+        Err(err) => WorkerError::new(ErrorKind::InvalidRequest, &err),
+    };
+    send(outgoing, Response::Error(error.clone())).await?;
+    anyhow::bail!("{}", error)
+}
 
+async fn send(outgoing: &Outgoing, response: Response) -> anyhow::Result<()> {
+    let message = Message::binary(response.to_bytes());
     outgoing
+        .lock()
+        .await
         .send(message)
         .await
-        .context("Failed to send render result")?;
+        .context("Failed to send response")
+}
+
+// Requests to the client waiting for their answers by scene md5 and path,
+// None once the connection is closed.
+struct PendingRequests<T>(std::sync::Mutex<Option<PendingAnswers<T>>>);
+
+type PendingAnswers<T> = HashMap<(String, String), oneshot::Sender<Result<T, String>>>;
+
+impl<T> PendingRequests<T> {
+    fn new() -> PendingRequests<T> {
+        PendingRequests(std::sync::Mutex::new(Some(HashMap::new())))
+    }
+
+    fn add(
+        &self,
+        scene_md5: &str,
+        path: &str,
+    ) -> anyhow::Result<oneshot::Receiver<Result<T, String>>> {
+        let (sender, receiver) = oneshot::channel();
+        self.0
+            .lock()
+            .unwrap()
+            .as_mut()
+            .context("Connection closed")?
+            .insert((scene_md5.to_string(), path.to_string()), sender);
+        Ok(receiver)
+    }
+
+    // Answers that weren't requested are ignored.
+    fn answer(&self, scene_md5: String, path: String, answer: Result<T, String>) {
+        let sender = self
+            .0
+            .lock()
+            .unwrap()
+            .as_mut()
+            .and_then(|pending| pending.remove(&(scene_md5, path.clone())));
+        match sender {
+            Some(sender) => {
+                let _ = sender.send(answer);
+            }
+            None => println!("Received answer for file {} that wasn't requested", path),
+        }
+    }
+
+    fn close(&self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+// Fetches scene files from the client over the connection.
+struct ClientFileStore {
+    outgoing: Outgoing,
+    files: PendingRequests<Vec<u8>>,
+    md5s: PendingRequests<String>,
+}
+
+impl ClientFileStore {
+    fn new(outgoing: Outgoing) -> ClientFileStore {
+        ClientFileStore {
+            outgoing,
+            files: PendingRequests::new(),
+            md5s: PendingRequests::new(),
+        }
+    }
+
+    // Fails the pending requests.
+    fn close(&self) {
+        self.files.close();
+        self.md5s.close();
+    }
+
+    async fn request<T>(
+        &self,
+        pending: &PendingRequests<T>,
+        scene_md5: &str,
+        path: &str,
+        request: Response,
+    ) -> anyhow::Result<T> {
+        let answer = pending.add(scene_md5, path)?;
+        send(&self.outgoing, request).await?;
+        answer
+            .await
+            .context("Connection closed")?
+            .map_err(anyhow::Error::msg)
+            .with_context(|| format!("Client failed to send file {}", path))
+    }
+}
+
+#[async_trait::async_trait]
+impl FileStore for ClientFileStore {
+    async fn fetch_file(&self, scene_md5: &str, path: &str) -> anyhow::Result<Vec<u8>> {
+        let request = Response::FetchFile {
+            scene_md5: scene_md5.to_string(),
+            path: path.to_string(),
+        };
+        self.request(&self.files, scene_md5, path, request).await
+    }
+
+    async fn file_md5(&self, scene_md5: &str, path: &str) -> anyhow::Result<Option<String>> {
+        let request = Response::FetchFileMd5 {
+            scene_md5: scene_md5.to_string(),
+            path: path.to_string(),
+        };
+        self.request(&self.md5s, scene_md5, path, request)
+            .await
+            .map(Some)
+    }
+}
+
+// Render running in the background, so the connection can receive cancel requests.
+struct RunningRender {
+    cancelled: Arc<AtomicBool>,
+    keep_partial: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl RunningRender {
+    // Starts once the previous render of the connection has sent its response.
+    fn start(
+        render_task: RenderTask,
+        progress: Option<ProgressInterval>,
+        previous: Option<RunningRender>,
+        worker: Arc<Mutex<Worker>>,
+        file_store: Arc<dyn FileStore>,
+        outgoing: Outgoing,
+    ) -> RunningRender {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let keep_partial = Arc::new(AtomicBool::new(false));
+
+        if let Some(previous) = &previous {
+            previous.cancel(false);
+        }
+
+        let handle = tokio::spawn({
+            let cancelled = cancelled.clone();
+            let keep_partial = keep_partial.clone();
+            async move {
+                if let Some(previous) = previous {
+                    let _ = previous.handle.await;
+                }
+
+                // Worker is held until the response is sent, so responses keep their order.
+                let worker = worker.lock_owned().await;
+                // Rendered in its own task, so a panic is reported instead of leaving
+                // the request unanswered.
+                let render = tokio::spawn(render(
+                    worker,
+                    render_task,
+                    file_store,
+                    progress,
+                    cancelled,
+                    keep_partial,
+                    outgoing.clone(),
+                ));
+                let (response, worker) = match render.await {
+                    Ok((response, worker)) => (response, Some(worker)),
+                    Err(err) => {
+                        let error = WorkerError::new(ErrorKind::Render, &err.into());
+                        println!("{}", error);
+                        (Response::Error(error), None)
+                    }
+                };
+
+                if let Err(err) = send(&outgoing, response).await {
+                    println!("Failed to send render result: {}", err);
+                }
+                drop(worker);
+            }
+        });
+
+        RunningRender {
+            cancelled,
+            keep_partial,
+            handle,
+        }
+    }
+
+    fn cancel(&self, keep_partial: bool) {
+        self.keep_partial.store(keep_partial, Ordering::Relaxed);
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+async fn render(
+    mut worker: OwnedMutexGuard<Worker>,
+    render_task: RenderTask,
+    file_store: Arc<dyn FileStore>,
+    progress: Option<ProgressInterval>,
+    cancelled: Arc<AtomicBool>,
+    keep_partial: Arc<AtomicBool>,
+    outgoing: Outgoing,
+) -> (Response, OwnedMutexGuard<Worker>) {
+    let send_progress = |image| {
+        let outgoing = outgoing.clone();
+        async move {
+            if let Err(err) = send(&outgoing, Response::Progress(image)).await {
+                println!("Failed to send render progress: {}", err);
+            }
+        }
+    };
+    let image = match worker
+        .render(
+            render_task,
+            file_store.as_ref(),
+            cancelled.clone(),
+            progress,
+            send_progress,
+        )
+        .await
+    {
+        Ok(image) => image,
+        Err(err) => {
+            println!("{}", err);
+            return (Response::Error(err), worker);
+        }
+    };
+
+    let discard = cancelled.load(Ordering::Relaxed)
+        && (!keep_partial.load(Ordering::Relaxed) || image.sample_count == 0);
+    let response = if discard {
+        Response::Cancelled
+    } else {
+        Response::Rendered(image)
+    };
+    (response, worker)
+}
+
+async fn connection_loop(
+    outgoing: Outgoing,
+    incoming: &mut SplitStream<WsStream>,
+    worker: Arc<Mutex<Worker>>,
+    file_store: Arc<dyn FileStore>,
+    client_files: &ClientFileStore,
+    current_render: &mut Option<RunningRender>,
+) -> anyhow::Result<()> {
+    let message = incoming
+        .next()
+        .await
+        .context("Connection closed")?
+        .context("Failed to receive message")?;
+
+    let request = match message {
+        Message::Binary(message) => Request::from_bytes(&message),
+        Message::Ping(_) | Message::Pong(_) => return Ok(()),
+        Message::Close(_) => anyhow::bail!("Connection closed"),
+        _ => Err(anyhow::anyhow!("Unexpected message format")),
+    };
+    // Invalid requests are reported to the client, the connection is kept.
+    let request = match request {
+        Ok(request) => request,
+        Err(err) => {
+            let error = WorkerError::new(ErrorKind::InvalidRequest, &err);
+            println!("{}", error);
+            return send(&outgoing, Response::Error(error)).await;
+        }
+    };
+
+    match request {
+        Request::Hello { .. } => {
+            let error = WorkerError {
+                kind: ErrorKind::InvalidRequest,
+                message: "Handshake is already done".to_string(),
+            };
+            send(&outgoing, Response::Error(error)).await?;
+        }
+        Request::Render {
+            render_task,
+            progress,
+        } => {
+            let previous = current_render.take();
+            *current_render = Some(RunningRender::start(
+                *render_task,
+                progress,
+                previous,
+                worker,
+                file_store,
+                outgoing,
+            ));
+        }
+        Request::Cancel { keep_partial } => {
+            if let Some(render) = current_render {
+                render.cancel(keep_partial);
+            }
+        }
+        Request::Ping => send(&outgoing, Response::Pong).await?,
+        Request::File {
+            scene_md5,
+            path,
+            data,
+        } => client_files.files.answer(scene_md5, path, data),
+        Request::FileMd5 {
+            scene_md5,
+            path,
+            md5,
+        } => client_files.md5s.answer(scene_md5, path, md5),
+    }
 
     Ok(())
 }
@@ -106,6 +560,7 @@ async fn listen_discovery_broadcasts() {
 
         let response = worker::discovery::Response {
             websocket_port: WEBSOCKET_PORT,
+            capabilities: Capabilities::current(),
         };
         let response = postcard::to_allocvec(&response).unwrap();
         socket.send_to(&response, sender).await.unwrap();
@@ -23,10 +23,13 @@ impl Ray {
         let min_point = Vec4::from_vec3(self.min * self.direction + self.source);
         let max_point = Vec4::from_vec3(self.max * self.direction + self.source);
 
+        let direction_point = Vec4::from_vec3(self.direction + self.source);
+
         let source = transform * source;
         let min_point = transform * min_point;
         let max_point = transform * max_point;
-        let direction = &transform.normal_matrix() * self.direction;
+        // Direction stays normalized as intersection code relies on it.
+        let direction = (transform * direction_point - source).normalized();
 
         Ray {
             source,
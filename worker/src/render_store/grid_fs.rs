@@ -0,0 +1,185 @@
+use anyhow::Context;
+use futures_util::{AsyncReadExt, StreamExt, io::AsyncWriteExt};
+use mongodb::{
+    Client, Database, GridFsBucket,
+    bson::{Document, doc},
+    options::ClientOptions,
+    options::{GridFsBucketOptions, GridFsFindOptions, GridFsUploadOptions},
+};
+
+use tokio::sync::Mutex;
+
+use super::{RenderMetadata, RenderStore, StoredRender, decode_render, encode_render};
+
+// Renders of every render task are kept in a bucket named by the render task md5,
+// with the size and id in file metadata.
+pub struct GridFsRenderStore {
+    database: Database,
+    // Renders older than the last saved one are removed.
+    prune: bool,
+    save_lock: Mutex<()>,
+}
+
+impl GridFsRenderStore {
+    pub async fn connect(mongodb_url: &str, prune: bool) -> anyhow::Result<GridFsRenderStore> {
+        let client_options = ClientOptions::parse(mongodb_url)
+            .await
+            .context("Incorrect MongoDB url")?;
+        let client =
+            Client::with_options(client_options).context("Failed to connect to MongoDB")?;
+
+        let database = client.database("render_outputs");
+
+        Ok(GridFsRenderStore {
+            database,
+            prune,
+            save_lock: Mutex::default(),
+        })
+    }
+
+    fn bucket(&self, render_task_md5: &str) -> GridFsBucket {
+        self.database.gridfs_bucket(Some(
+            GridFsBucketOptions::builder()
+                .bucket_name(render_task_md5.to_string())
+                .build(),
+        ))
+    }
+
+    fn parse_metadata(metadata: Option<Document>) -> anyhow::Result<RenderMetadata> {
+        let metadata = metadata.context("Extraneous file in database: Expected metadata")?;
+        let get = |key| {
+            metadata
+                .get_i32(key)
+                .with_context(|| {
+                    format!(
+                        "Extraneous file in database: Wrong metadata format [{}], expected {} as integer",
+                        metadata, key
+                    )
+                })
+                .map(|value| value as u32)
+        };
+        Ok(RenderMetadata {
+            width: get("width")?,
+            height: get("height")?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RenderStore for GridFsRenderStore {
+    async fn save_render(
+        &self,
+        render_task_md5: &str,
+        render: &StoredRender,
+    ) -> anyhow::Result<()> {
+        let data = encode_render(render)?;
+        let bucket = self.bucket(render_task_md5);
+
+        let _save_lock = self.save_lock.lock().await;
+        let render_count = self.render_count(render_task_md5).await?;
+        let id = render_count as i64;
+
+        // Files are only found once closed, so renders that weren't uploaded completely
+        // aren't counted.
+        let mut upload_stream = bucket.open_upload_stream(
+            format!("{}", render_count),
+            Some(
+                GridFsUploadOptions::builder()
+                    .metadata(Some(doc! {
+                        "width": render.image.width() as i32,
+                        "height": render.image.height() as i32,
+                        "id": id
+                    }))
+                    .build(),
+            ),
+        );
+
+        upload_stream
+            .write_all(&data)
+            .await
+            .context("Failed to upload render")?;
+        upload_stream
+            .close()
+            .await
+            .context("Failed to upload render")?;
+
+        if self.prune {
+            let older_files = bucket
+                .find(doc! { "metadata.id": { "$lt": id } }, None)
+                .await
+                .context("Failed to find older renders")?
+                .collect::<Vec<_>>()
+                .await;
+            for file in older_files {
+                let file = file.context("Failed to find older renders")?;
+                bucket
+                    .delete(file.id)
+                    .await
+                    .context("Failed to delete older render")?;
+            }
+        }
+
+        println!("Render {} saved", render_count);
+        Ok(())
+    }
+
+    async fn render_count(&self, render_task_md5: &str) -> anyhow::Result<usize> {
+        let options = GridFsFindOptions::builder()
+            .sort(doc! { "metadata.id": -1 })
+            .limit(1)
+            .build();
+        let last_file = self
+            .bucket(render_task_md5)
+            .find(doc! {}, options)
+            .await
+            .context("Failed to list renders")?
+            .next()
+            .await;
+        let Some(last_file) = last_file else {
+            return Ok(0);
+        };
+        let last_file = last_file.context("Failed to list renders")?;
+        let last_id = last_file
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.get_i64("id").ok())
+            .context("Extraneous file in database: Expected id as integer in metadata")?;
+        Ok(last_id as usize + 1)
+    }
+
+    async fn load_render(
+        &self,
+        id: usize,
+        render_task_md5: &str,
+    ) -> anyhow::Result<Option<StoredRender>> {
+        let bucket = self.bucket(render_task_md5);
+
+        let mut found_files = bucket
+            .find(doc! { "metadata.id": id as i64 }, None)
+            .await
+            .context("Failed to find render")?
+            .collect::<Vec<_>>()
+            .await;
+        let found_file = match found_files.len() {
+            0 => return Ok(None),
+            1 => found_files
+                .pop()
+                .unwrap()
+                .context("Failed to find render")?,
+            _ => anyhow::bail!("Extraneous file in database: Render {} is duplicated", id),
+        };
+        let metadata = Self::parse_metadata(found_file.metadata)?;
+
+        let mut stream = bucket
+            .open_download_stream(found_file.id)
+            .await
+            .context("Failed to download render")?;
+        let mut render_data = vec![];
+        stream
+            .read_to_end(&mut render_data)
+            .await
+            .context("Failed to download render")?;
+
+        decode_render(&metadata, &render_data).map(Some)
+    }
+}
@@ -0,0 +1,237 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use tokio::sync::Mutex;
+
+use super::{RenderMetadata, RenderStore, StoredRender, decode_render, encode_render};
+
+// Renders of every render task are kept in a directory named by the render task md5,
+// as "<id>.raw" files with the size in "<id>.json".
+pub struct LocalRenderStore {
+    root: PathBuf,
+    // Renders older than the last saved one are removed.
+    prune: bool,
+    save_lock: Mutex<()>,
+}
+
+impl LocalRenderStore {
+    pub fn new(root: PathBuf, prune: bool) -> LocalRenderStore {
+        LocalRenderStore {
+            root,
+            prune,
+            save_lock: Mutex::default(),
+        }
+    }
+
+    fn render_dir(&self, render_task_md5: &str) -> PathBuf {
+        self.root.join(render_task_md5)
+    }
+
+    async fn read(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        match tokio::fs::read(path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    // Files of the directory named by render ids, along with the ids.
+    async fn render_files(dir: &Path) -> anyhow::Result<Vec<(usize, PathBuf)>> {
+        let mut entries = match tokio::fs::read_dir(dir).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("Failed to read directory {}", dir.display()));
+            }
+        };
+
+        let mut files = vec![];
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .with_context(|| format!("Failed to read directory {}", dir.display()))?
+        {
+            let path = entry.path();
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            if let Some(id) = id {
+                files.push((id, path));
+            }
+        }
+        Ok(files)
+    }
+}
+
+#[async_trait::async_trait]
+impl RenderStore for LocalRenderStore {
+    async fn save_render(
+        &self,
+        render_task_md5: &str,
+        render: &StoredRender,
+    ) -> anyhow::Result<()> {
+        let data = encode_render(render)?;
+        let dir = self.render_dir(render_task_md5);
+        tokio::fs::create_dir_all(&dir)
+            .await
+            .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+
+        let _save_lock = self.save_lock.lock().await;
+        let render_count = self.render_count(render_task_md5).await?;
+
+        let data_path = dir.join(format!("{}.raw", render_count));
+        tokio::fs::write(&data_path, data)
+            .await
+            .with_context(|| format!("Failed to write {}", data_path.display()))?;
+
+        // Written last and renamed into place, so renders that weren't saved completely
+        // aren't counted. Files left by a failed save are replaced by the next one.
+        let metadata = RenderMetadata {
+            width: render.image.width(),
+            height: render.image.height(),
+        };
+        let metadata_path = dir.join(format!("{}.json", render_count));
+        let temp_path = dir.join(format!("{}.tmp", render_count));
+        tokio::fs::write(&temp_path, serde_json::to_vec(&metadata)?)
+            .await
+            .with_context(|| format!("Failed to write {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &metadata_path)
+            .await
+            .with_context(|| format!("Failed to write {}", metadata_path.display()))?;
+
+        if self.prune {
+            for (id, path) in Self::render_files(&dir).await? {
+                if id < render_count {
+                    tokio::fs::remove_file(&path)
+                        .await
+                        .with_context(|| format!("Failed to remove {}", path.display()))?;
+                }
+            }
+        }
+
+        println!("Render {} saved", render_count);
+        Ok(())
+    }
+
+    async fn render_count(&self, render_task_md5: &str) -> anyhow::Result<usize> {
+        let dir = self.render_dir(render_task_md5);
+        let last_id = Self::render_files(&dir)
+            .await?
+            .into_iter()
+            .filter(|(_, path)| path.extension().is_some_and(|ext| ext == "json"))
+            .map(|(id, _)| id)
+            .max();
+        Ok(last_id.map_or(0, |id| id + 1))
+    }
+
+    async fn load_render(
+        &self,
+        id: usize,
+        render_task_md5: &str,
+    ) -> anyhow::Result<Option<StoredRender>> {
+        let dir = self.render_dir(render_task_md5);
+        let metadata_path = dir.join(format!("{}.json", id));
+        let Some(metadata) = Self::read(&metadata_path).await? else {
+            return Ok(None);
+        };
+        let metadata: RenderMetadata = serde_json::from_slice(&metadata)
+            .with_context(|| format!("Incorrect render metadata {}", metadata_path.display()))?;
+
+        let data_path = dir.join(format!("{}.raw", id));
+        let data = Self::read(&data_path)
+            .await?
+            .with_context(|| format!("Render data {} is missing", data_path.display()))?;
+
+        decode_render(&metadata, &data)
+            .with_context(|| format!("Failed to load render {}", data_path.display()))
+            .map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every pixel and sample count differs, so flipped rows are noticed.
+    fn test_render(seed: f32) -> StoredRender {
+        let (width, height) = (2, 3);
+        let pixels = (0..width * height * 3).map(|i| seed + i as f32).collect();
+        StoredRender {
+            image: image::Rgb32FImage::from_vec(width, height, pixels).unwrap(),
+            sample_counts: (1..=width * height).collect(),
+        }
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut files: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn saved_renders_are_loaded_back() {
+        let root = std::env::temp_dir().join(format!("render_store_test_{}", std::process::id()));
+        let render_store = LocalRenderStore::new(root.clone(), false);
+        let renders = [test_render(0.0), test_render(100.0)];
+
+        for render in &renders {
+            render_store.save_render("task", render).await.unwrap();
+        }
+        assert_eq!(render_store.render_count("task").await.unwrap(), 2);
+        assert_eq!(render_store.render_count("other_task").await.unwrap(), 0);
+
+        for (id, render) in renders.iter().enumerate() {
+            let loaded = render_store.load_render(id, "task").await.unwrap().unwrap();
+            assert_eq!(loaded.image, render.image);
+            assert_eq!(loaded.sample_counts, render.sample_counts);
+        }
+        assert!(render_store.load_render(2, "task").await.unwrap().is_none());
+        assert_eq!(
+            file_names(&root.join("task")),
+            ["0.json", "0.raw", "1.json", "1.raw"]
+        );
+
+        // The bottom row is stored first.
+        let data = std::fs::read(root.join("task/0.raw")).unwrap();
+        assert_eq!(f32::from_be_bytes(data[..4].try_into().unwrap()), 12.0);
+        let sample_counts = &data[2 * 3 * 3 * 4..];
+        assert_eq!(
+            u32::from_be_bytes(sample_counts[..4].try_into().unwrap()),
+            5
+        );
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn failed_and_pruned_renders_are_removed() {
+        let root = std::env::temp_dir().join(format!("render_prune_test_{}", std::process::id()));
+        let render_store = LocalRenderStore::new(root.clone(), true);
+        let render = test_render(0.0);
+
+        render_store.save_render("task", &render).await.unwrap();
+        // Left by a failed save.
+        std::fs::write(root.join("task/1.raw"), [0]).unwrap();
+        std::fs::write(root.join("task/1.tmp"), [0]).unwrap();
+        assert_eq!(render_store.render_count("task").await.unwrap(), 1);
+
+        render_store.save_render("task", &render).await.unwrap();
+        render_store.save_render("task", &render).await.unwrap();
+        assert_eq!(render_store.render_count("task").await.unwrap(), 3);
+        assert_eq!(file_names(&root.join("task")), ["2.json", "2.raw"]);
+        assert!(render_store.load_render(1, "task").await.unwrap().is_none());
+        let loaded = render_store.load_render(2, "task").await.unwrap().unwrap();
+        assert_eq!(loaded.image, render.image);
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
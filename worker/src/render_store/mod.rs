@@ -0,0 +1,91 @@
+use image::Rgb32FImage;
+use serde::{Deserialize, Serialize};
+
+mod grid_fs;
+mod local;
+
+pub use grid_fs::GridFsRenderStore;
+pub use local::LocalRenderStore;
+
+// Keeps renders of every render task, numbered from 0 in the order they were saved.
+// Every render holds all the samples of the task saved before it, so the last one
+// is enough to resume the task.
+#[async_trait::async_trait]
+pub trait RenderStore: Send + Sync {
+    // Saves are serialized, so concurrent ones don't get the same id.
+    async fn save_render(&self, render_task_md5: &str, render: &StoredRender)
+    -> anyhow::Result<()>;
+    // Pruned renders are counted too.
+    async fn render_count(&self, render_task_md5: &str) -> anyhow::Result<usize>;
+    // Pruned renders aren't found.
+    async fn load_render(
+        &self,
+        id: usize,
+        render_task_md5: &str,
+    ) -> anyhow::Result<Option<StoredRender>>;
+}
+
+// Render with every pixel averaged from its own number of samples.
+pub struct StoredRender {
+    pub image: Rgb32FImage,
+    // Row by row from the top, as pixels of the image.
+    pub sample_counts: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RenderMetadata {
+    width: u32,
+    height: u32,
+}
+
+// Renders are stored as big endian floats followed by sample counts, bottom row first.
+fn encode_render(render: &StoredRender) -> anyhow::Result<Vec<u8>> {
+    if render.sample_counts.len() != render.image.pixels().len() {
+        anyhow::bail!("Sample counts don't match the render size");
+    }
+
+    let width = render.image.width().max(1) as usize;
+    let pixels = render
+        .image
+        .as_raw()
+        .chunks(3 * width)
+        .rev()
+        .flatten()
+        .flat_map(|value| value.to_be_bytes());
+    let sample_counts = render
+        .sample_counts
+        .chunks(width)
+        .rev()
+        .flatten()
+        .flat_map(|value| value.to_be_bytes());
+    Ok(pixels.chain(sample_counts).collect())
+}
+
+fn decode_render(metadata: &RenderMetadata, data: &[u8]) -> anyhow::Result<StoredRender> {
+    let width = metadata.width.max(1) as usize;
+    let pixel_count = (metadata.width * metadata.height) as usize;
+    if data.len() != pixel_count * 16 {
+        anyhow::bail!("Render data doesn't match its size");
+    }
+
+    let (pixels, sample_counts) = data.split_at(pixel_count * 12);
+    let pixels: Vec<_> = pixels
+        .chunks_exact(4)
+        .map(|bytes| f32::from_be_bytes(bytes.try_into().unwrap()))
+        .collect();
+    let sample_counts: Vec<_> = sample_counts
+        .chunks_exact(4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .collect();
+
+    let pixels = pixels.chunks(3 * width).rev().flatten().copied().collect();
+    Ok(StoredRender {
+        image: Rgb32FImage::from_vec(metadata.width, metadata.height, pixels).unwrap(),
+        sample_counts: sample_counts
+            .chunks(width)
+            .rev()
+            .flatten()
+            .copied()
+            .collect(),
+    })
+}
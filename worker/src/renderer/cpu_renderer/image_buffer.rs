@@ -23,6 +23,10 @@ impl ImageBuffer {
         self.pixels[x + y * self.width]
     }
 
+    pub fn clear(&mut self) {
+        self.pixels.fill(Vec3::default());
+    }
+
     pub fn get_pixel_vec(&self, color_multiplier: f32) -> Vec<Vec<HdrColor>> {
         let mut image_data: Vec<Vec<HdrColor>> = Vec::with_capacity(self.width);
 
@@ -9,7 +9,7 @@ mod image_buffer;
 mod work_group;
 
 use super::Renderer;
-use crate::{api::render_task::RenderTask, ray::Ray, scene::Scene};
+use crate::{aabb::Aabb, api::render_task::RenderTask, ray::Ray, sampler::Sampler, scene::Scene};
 use work_group::WorkGroup;
 
 pub struct RayTraceResult {
@@ -22,6 +22,8 @@ pub struct RayTraceResult {
     pub t: f32,
 
     pub material_id: usize,
+    // Surface is in the scene light list, so it can be hit by light sampling.
+    pub is_light: bool,
 }
 
 impl RayTraceResult {
@@ -34,75 +36,105 @@ impl RayTraceResult {
             t: 0.0,
             material_id: 0,
             hit_inside: false,
+            is_light: false,
         }
     }
 }
 
 pub trait SceneNode: Send + Sync {
     fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult;
+    // Unbounded nodes (e.g. planes) return Aabb::infinite().
+    fn bounds(&self, scene: &Scene) -> Aabb;
+
+    // Nodes are split into primitives so acceleration structures can be built over them.
+    fn primitive_count(&self, _: &Scene) -> usize {
+        1
+    }
+    fn primitive_bounds(&self, scene: &Scene, _: usize) -> Aabb {
+        self.bounds(scene)
+    }
+    fn trace_primitive(&self, scene: Arc<Scene>, ray: &Ray, _: usize) -> RayTraceResult {
+        self.trace_ray(scene, ray)
+    }
+
+    // Called once after all scene resources are loaded.
+    fn prepare(&mut self, _: &mut Scene) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
-pub enum GetColorResult {
-    Color(Vec3),
-    NextRayColorMultiplierAndDirection(Vec3, Vec3),
+pub struct BsdfSample {
+    pub wi: Vec3,
+    // BSDF multiplied by cosine and divided by pdf.
+    pub weight: Vec3,
+    // Not defined for delta lobes.
+    pub pdf: f32,
+    // Delta lobes (mirror reflection, refraction) can't be evaluated or hit by light sampling.
+    pub is_delta: bool,
 }
 
+// Both wi and wo point away from the surface, wo is towards the viewer.
+// Normal in RayTraceResult always faces the incoming ray.
 pub trait Material: Send + Sync {
-    fn get_color(
+    // Returns None if the path is absorbed.
+    fn sample(
         &self,
-        dir: Vec3,
+        wo: Vec3,
         trace_result: &RayTraceResult,
         scene: Arc<Scene>,
-    ) -> GetColorResult;
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample>;
+
+    // BSDF multiplied by cosine of wi, delta lobes are ignored.
+    fn eval(&self, wi: Vec3, wo: Vec3, trace_result: &RayTraceResult, scene: Arc<Scene>) -> Vec3;
+    // Pdf of sampling wi, delta lobes are ignored.
+    fn pdf(&self, wi: Vec3, wo: Vec3, trace_result: &RayTraceResult) -> f32;
+
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    // Radiance emitted towards wo.
+    fn emitted(&self, _wo: Vec3, _: &RayTraceResult) -> Vec3 {
+        Vec3::default()
+    }
 }
 
 pub struct CPURenderer {
     scene: Arc<Scene>,
+    render_task: Option<Arc<RenderTask>>,
 
     workgroup_count: UVec2,
     workgroup_size: UVec2,
     workgroups: Vec<WorkGroup>,
+    completed_iterations: usize,
+    taken_iterations: usize,
+    taken_capped_paths: usize,
     thread_pool: ThreadPool,
 }
 
 impl CPURenderer {
-    pub fn iterations(&mut self, render_task: Arc<RenderTask>) {
-        let (tx, rx) = channel::<(WorkGroup, usize)>();
-
-        for _ in 0..render_task.config.iterations {
-            let workgroup_count = self.workgroup_count.x * self.workgroup_count.y;
-
-            let mut workgroups_received = vec![];
-            for _ in 0..workgroup_count {
-                workgroups_received.push(None);
-            }
+    fn render_task(&self) -> Arc<RenderTask> {
+        self.render_task
+            .clone()
+            .expect("Render must be started before iterations")
+    }
 
-            for i in 0..workgroup_count {
-                let tx_ = tx.clone();
-                let mut workgroup = self.workgroups.remove(0);
-                let scene = self.scene.clone();
-                let render_task = render_task.clone();
-                self.thread_pool.execute(move || {
-                    workgroup.iteration(scene.clone(), render_task);
-                    tx_.send((workgroup, i)).unwrap();
-                });
-            }
-            for _ in 0..workgroup_count {
-                let (workgroup, id) = rx.recv().unwrap();
-                workgroups_received[id] = Some(workgroup);
-            }
-            for workgroup in workgroups_received {
-                self.workgroups.push(workgroup.unwrap());
-            }
-        }
+    fn capped_paths(&self) -> usize {
+        self.workgroups
+            .iter()
+            .map(|workgroup| workgroup.capped_paths)
+            .sum()
     }
 
     fn divide_to_workgroups(&self, render_task: &RenderTask) -> (UVec2, Vec<WorkGroup>) {
         let mut workgroups = Vec::new();
 
+        let crop = render_task.crop();
+        let origin = Self::crop_origin(render_task);
+
         // Number of full-widthed and full-heighted workgroups
-        let mut workgroup_count = render_task.camera.resolution / self.workgroup_size;
-        let remainder = render_task.camera.resolution - workgroup_count * self.workgroup_size;
+        let mut workgroup_count = crop.size / self.workgroup_size;
+        let remainder = crop.size - workgroup_count * self.workgroup_size;
         if remainder.x != 0 {
             workgroup_count.x += 1;
         }
@@ -115,20 +147,20 @@ impl CPURenderer {
         for row_id in 0..workgroup_count.y {
             let mut row_height = self.workgroup_size.y;
             // Last row can be not full-heighted
-            if row_id == workgroup_count.y && remainder.y != 0 {
+            if row_id == workgroup_count.y - 1 && remainder.y != 0 {
                 row_height = remainder.y;
             }
 
             for column_id in 0..workgroup_count.x {
                 let mut column_width = self.workgroup_size.x;
                 // Last column can be not full-widthed
-                if column_id == workgroup_count.x && remainder.x != 0 {
+                if column_id == workgroup_count.x - 1 && remainder.x != 0 {
                     column_width = remainder.x;
                 }
 
                 let workgroup = WorkGroup::new(
-                    column_id * self.workgroup_size.x,
-                    row_id * self.workgroup_size.y,
+                    origin.x + column_id * self.workgroup_size.x,
+                    origin.y + row_id * self.workgroup_size.y,
                     column_width,
                     row_height,
                 );
@@ -140,56 +172,102 @@ impl CPURenderer {
         (workgroup_count, workgroups)
     }
 
-    pub fn get_image(&self, render_task: &RenderTask) -> Rgb32FImage {
-        let mut buffer: Vec<f32> =
-            vec![0.0; render_task.camera.resolution.x * render_task.camera.resolution.y * 3];
-
-        for x in 0..self.workgroup_count.x {
-            for y in 0..self.workgroup_count.y {
-                let workgroup_buffer =
-                    self.workgroups[x + y * self.workgroup_count.x].get_raw_image_data();
-
-                for buf_x in 0..workgroup_buffer.len() {
-                    let glob_x = x * self.workgroup_size.x + buf_x;
-                    #[allow(clippy::needless_range_loop)]
-                    for buf_y in 0..workgroup_buffer[0].len() {
-                        let buf_pixel = workgroup_buffer[buf_x][buf_y];
-                        let glob_y = y * self.workgroup_size.y + buf_y;
-                        let glob_y = render_task.camera.resolution.y - 1 - glob_y;
-                        let glob_adress = glob_x + glob_y * render_task.camera.resolution.x;
-
-                        buffer[glob_adress * 3] = buf_pixel.r;
-                        buffer[glob_adress * 3 + 1] = buf_pixel.g;
-                        buffer[glob_adress * 3 + 2] = buf_pixel.b;
-                    }
+    // Crop is in image coordinates, camera pixels start at the bottom left corner.
+    fn crop_origin(render_task: &RenderTask) -> UVec2 {
+        let crop = render_task.crop();
+        UVec2::new(
+            crop.offset.x,
+            render_task.camera.resolution.y - crop.offset.y - crop.size.y,
+        )
+    }
+
+    fn take_workgroup_images(&mut self, render_task: &RenderTask) -> Rgb32FImage {
+        let crop = render_task.crop();
+        let origin = Self::crop_origin(render_task);
+        let mut buffer: Vec<f32> = vec![0.0; crop.size.x * crop.size.y * 3];
+
+        for workgroup in &mut self.workgroups {
+            let workgroup_buffer = workgroup.take_raw_image_data();
+
+            for (buf_x, column) in workgroup_buffer.iter().enumerate() {
+                let glob_x = workgroup.x_offset + buf_x - origin.x;
+                for (buf_y, buf_pixel) in column.iter().enumerate() {
+                    let glob_y = workgroup.y_offset + buf_y - origin.y;
+                    let glob_y = crop.size.y - 1 - glob_y;
+                    let glob_adress = glob_x + glob_y * crop.size.x;
+
+                    buffer[glob_adress * 3] = buf_pixel.r;
+                    buffer[glob_adress * 3 + 1] = buf_pixel.g;
+                    buffer[glob_adress * 3 + 2] = buf_pixel.b;
                 }
             }
         }
 
-        Rgb32FImage::from_raw(
-            render_task.camera.resolution.x as u32,
-            render_task.camera.resolution.y as u32,
-            buffer,
-        )
-        .unwrap()
+        Rgb32FImage::from_raw(crop.size.x as u32, crop.size.y as u32, buffer).unwrap()
     }
 }
 
-#[async_trait::async_trait]
 impl Renderer for CPURenderer {
     fn init(scene: Arc<Scene>) -> CPURenderer {
         CPURenderer {
             scene,
+            render_task: None,
             workgroup_count: UVec2::default(),
             workgroup_size: UVec2::new(32, 32),
             workgroups: vec![],
+            completed_iterations: 0,
+            taken_iterations: 0,
+            taken_capped_paths: 0,
             thread_pool: ThreadPool::new(num_cpus::get()),
         }
     }
 
-    async fn render(&mut self, render_task: Arc<RenderTask>) -> Rgb32FImage {
+    fn start(&mut self, render_task: Arc<RenderTask>) {
         (self.workgroup_count, self.workgroups) = self.divide_to_workgroups(&render_task);
-        self.iterations(render_task.clone());
-        self.get_image(&render_task)
+        self.completed_iterations = 0;
+        self.taken_iterations = 0;
+        self.taken_capped_paths = 0;
+        self.render_task = Some(render_task);
+    }
+
+    fn iteration(&mut self) {
+        let render_task = self.render_task();
+        let (tx, rx) = channel::<(WorkGroup, usize)>();
+
+        let workgroup_count = self.workgroup_count.x * self.workgroup_count.y;
+
+        let mut workgroups_received = vec![];
+        for _ in 0..workgroup_count {
+            workgroups_received.push(None);
+        }
+
+        for i in 0..workgroup_count {
+            let tx_ = tx.clone();
+            let mut workgroup = self.workgroups.remove(0);
+            let scene = self.scene.clone();
+            let render_task = render_task.clone();
+            self.thread_pool.execute(move || {
+                workgroup.iteration(scene.clone(), render_task);
+                tx_.send((workgroup, i)).unwrap();
+            });
+        }
+        for _ in 0..workgroup_count {
+            let (workgroup, id) = rx.recv().unwrap();
+            workgroups_received[id] = Some(workgroup);
+        }
+        for workgroup in workgroups_received {
+            self.workgroups.push(workgroup.unwrap());
+        }
+        self.completed_iterations += 1;
+    }
+
+    fn take_image(&mut self) -> (Rgb32FImage, usize, usize) {
+        let render_task = self.render_task();
+        let image = self.take_workgroup_images(&render_task);
+        let sample_count = self.completed_iterations - self.taken_iterations;
+        self.taken_iterations = self.completed_iterations;
+        let capped_paths = self.capped_paths() - self.taken_capped_paths;
+        self.taken_capped_paths += capped_paths;
+        (image, sample_count, capped_paths)
     }
 }
@@ -2,13 +2,22 @@ use std::sync::Arc;
 
 use math::{HdrColor, UVec2, Vec3};
 
-use super::{GetColorResult, image_buffer::ImageBuffer};
-use crate::{api::render_task::RenderTask, ray::Ray, scene::Scene};
+use super::{Material, RayTraceResult, image_buffer::ImageBuffer};
+use crate::{
+    api::render_task::{Config, RenderTask},
+    ray::Ray,
+    sampler::Sampler,
+    scene::Scene,
+};
 
 pub struct WorkGroup {
     iteration: usize,
-    x_offset: usize,
-    y_offset: usize,
+    // Iteration of the last taken image, buffer holds only samples rendered after it.
+    taken_iteration: usize,
+    // Number of paths terminated by reaching trace depth.
+    pub capped_paths: usize,
+    pub x_offset: usize,
+    pub y_offset: usize,
 
     pub buffer: ImageBuffer,
 }
@@ -17,46 +26,171 @@ impl WorkGroup {
     pub fn new(x_offset: usize, y_offset: usize, width: usize, height: usize) -> WorkGroup {
         WorkGroup {
             iteration: 0,
+            taken_iteration: 0,
+            capped_paths: 0,
             x_offset,
             y_offset,
             buffer: ImageBuffer::new(width, height),
         }
     }
 
-    fn get_color(&mut self, scene_data: Arc<Scene>, mut ray: Ray, max_depth: usize) -> Vec3 {
+    fn trace_shadow_ray(scene_data: Arc<Scene>, point: Vec3, dir: Vec3, distance: f32) -> bool {
+        let ray_start = point + dir * math::EPSILON;
+        let ray = Ray::new(
+            ray_start,
+            dir,
+            math::EPSILON,
+            distance - 2.0 * math::EPSILON,
+        );
+        !scene_data.hierarchy.trace_ray(scene_data.clone(), &ray).hit
+    }
+
+    // Samples a light and returns its contribution weighted with multiple importance sampling.
+    fn sample_light(
+        scene_data: Arc<Scene>,
+        material: &dyn Material,
+        wo: Vec3,
+        trace_result: &RayTraceResult,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let selection = sampler.get_1d();
+        let point = sampler.get_2d();
+        let rand = [selection, point.x, point.y];
+        let environment_probability = scene_data.environment_light_probability();
+        let (direction, distance, pdf, emitted) = if rand[0] < environment_probability {
+            let (direction, pdf) = scene_data.environment.sample(rand[1], rand[2]);
+            let emitted = scene_data.environment.radiance(&scene_data, direction);
+            (direction, f32::MAX, pdf * environment_probability, emitted)
+        } else {
+            let rand_0 = (rand[0] - environment_probability) / (1.0 - environment_probability);
+            let Some(sample) = scene_data
+                .lights
+                .sample(trace_result.point, [rand_0, rand[1], rand[2]])
+            else {
+                return Vec3::default();
+            };
+            let light_material = scene_data.materials[sample.trace_result.material_id].as_ref();
+            let emitted = light_material.emitted(sample.direction * -1.0, &sample.trace_result);
+            (
+                sample.direction,
+                sample.distance,
+                sample.pdf * (1.0 - environment_probability),
+                emitted,
+            )
+        };
+        if pdf <= 0.0 || !pdf.is_finite() {
+            return Vec3::default();
+        }
+        if emitted.x <= 0.0 && emitted.y <= 0.0 && emitted.z <= 0.0 {
+            return Vec3::default();
+        }
+
+        let bsdf = material.eval(direction, wo, trace_result, scene_data.clone());
+        if bsdf.x <= 0.0 && bsdf.y <= 0.0 && bsdf.z <= 0.0 {
+            return Vec3::default();
+        }
+
+        let visible =
+            Self::trace_shadow_ray(scene_data.clone(), trace_result.point, direction, distance);
+        if !visible {
+            return Vec3::default();
+        }
+
+        let bsdf_pdf = material.pdf(direction, wo, trace_result);
+        let weight = power_heuristic(pdf, bsdf_pdf);
+        bsdf * emitted * (weight / pdf)
+    }
+
+    fn get_color(
+        &mut self,
+        scene_data: Arc<Scene>,
+        mut ray: Ray,
+        config: &Config,
+        sampler: &mut dyn Sampler,
+    ) -> Vec3 {
+        let mut color = Vec3::default();
         let mut multiplier = Vec3::new_xyz(1.0);
+        // None for camera rays and delta lobes which can't be sampled by lights.
+        let mut last_bsdf_pdf = None;
 
-        for _ in 0..max_depth {
+        for depth in 0..config.trace_depth {
             let trace_result = scene_data.hierarchy.trace_ray(scene_data.clone(), &ray);
             if !trace_result.hit {
-                return Vec3::default();
+                let radiance = scene_data.environment.radiance(&scene_data, ray.direction);
+                let weight = match last_bsdf_pdf {
+                    Some(bsdf_pdf) => {
+                        let environment_pdf = scene_data.environment_light_probability()
+                            * scene_data.environment.pdf(ray.direction);
+                        power_heuristic(bsdf_pdf, environment_pdf)
+                    }
+                    None => 1.0,
+                };
+                return color + multiplier * radiance * weight;
             }
 
             let material = scene_data.materials[trace_result.material_id].as_ref();
-            let color_result = material.get_color(ray.direction, &trace_result, scene_data.clone());
+            let wo = ray.direction * -1.0;
 
-            match color_result {
-                GetColorResult::Color(color) => {
-                    return multiplier * color;
+            let emitted = material.emitted(wo, &trace_result);
+            let emitted_weight = match last_bsdf_pdf {
+                Some(bsdf_pdf) if trace_result.is_light => {
+                    let light_cos = ray.direction.dot(trace_result.normal);
+                    let distance = (trace_result.point - ray.source).length();
+                    let light_pdf = scene_data.lights.pdf(distance, light_cos)
+                        * (1.0 - scene_data.environment_light_probability());
+                    power_heuristic(bsdf_pdf, light_pdf)
                 }
-                GetColorResult::NextRayColorMultiplierAndDirection(mul, dir) => {
-                    let ray_start = trace_result.point + dir * math::EPSILON;
-                    ray = Ray::new(ray_start, dir, math::EPSILON, f32::MAX);
-                    multiplier = multiplier * mul;
+                _ => 1.0,
+            };
+            color = color + multiplier * emitted * emitted_weight;
+
+            color = color
+                + multiplier
+                    * Self::sample_light(scene_data.clone(), material, wo, &trace_result, sampler);
+
+            let Some(sample) = material.sample(wo, &trace_result, scene_data.clone(), sampler)
+            else {
+                return color;
+            };
+
+            let ray_start = trace_result.point + sample.wi * math::EPSILON;
+            ray = Ray::new(ray_start, sample.wi, math::EPSILON, f32::MAX);
+            multiplier = multiplier * sample.weight;
+            last_bsdf_pdf = (!sample.is_delta).then_some(sample.pdf);
+
+            if depth + 1 >= config.russian_roulette_depth {
+                let survive_probability = multiplier
+                    .x
+                    .max(multiplier.y)
+                    .max(multiplier.z)
+                    .clamp(config.russian_roulette_min_probability, 1.0);
+                if sampler.get_1d() >= survive_probability {
+                    return color;
                 }
+                multiplier = multiplier / survive_probability;
             }
         }
 
-        Vec3::default()
+        self.capped_paths += 1;
+        color
     }
 
     pub fn iteration(&mut self, scene_data: Arc<Scene>, render_task: Arc<RenderTask>) {
         for x in 0..self.buffer.width {
             for y in 0..self.buffer.height {
-                let ray = render_task
-                    .camera
-                    .get_ray(UVec2::new(self.x_offset + x, self.y_offset + y));
-                let color = self.get_color(scene_data.clone(), ray, render_task.config.trace_depth);
+                let pixel = UVec2::new(self.x_offset + x, self.y_offset + y);
+                let config = &render_task.config;
+                let mut sampler =
+                    config
+                        .sampler
+                        .create(config.seed, pixel, self.iteration, config.iterations);
+                let ray = render_task.camera.get_ray(pixel, sampler.as_mut());
+                let color = self.get_color(
+                    scene_data.clone(),
+                    ray,
+                    &render_task.config,
+                    sampler.as_mut(),
+                );
                 let pixel = self.buffer.get_pixel_mut(x, y);
                 *pixel = *pixel + color;
             }
@@ -64,7 +198,19 @@ impl WorkGroup {
         self.iteration += 1;
     }
 
-    pub fn get_raw_image_data(&self) -> Vec<Vec<HdrColor>> {
-        self.buffer.get_pixel_vec(1.0 / (self.iteration as f32))
+    // Returns the average of samples since the previous call and clears the buffer.
+    pub fn take_raw_image_data(&mut self) -> Vec<Vec<HdrColor>> {
+        let sample_count = self.iteration - self.taken_iteration;
+        let data = self
+            .buffer
+            .get_pixel_vec(1.0 / (sample_count.max(1) as f32));
+        self.buffer.clear();
+        self.taken_iteration = self.iteration;
+        data
     }
 }
+
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let pdf_sqr = pdf * pdf;
+    pdf_sqr / (pdf_sqr + other_pdf * other_pdf)
+}
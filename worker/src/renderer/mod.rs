@@ -6,8 +6,12 @@ use image::Rgb32FImage;
 
 use crate::{api::render_task::RenderTask, scene::Scene};
 
-#[async_trait::async_trait]
 pub trait Renderer {
     fn init(scene: Arc<Scene>) -> Self;
-    async fn render(&mut self, render_task: Arc<RenderTask>) -> Rgb32FImage;
+    fn start(&mut self, render_task: Arc<RenderTask>);
+    // Adds one sample to every pixel.
+    fn iteration(&mut self);
+    // Average of the samples rendered since the previous call, their count
+    // and how many of their paths were cut at the trace depth.
+    fn take_image(&mut self) -> (Rgb32FImage, usize, usize);
 }
@@ -0,0 +1,179 @@
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+
+// Files in the cache directory are removed once they take more than this.
+const MAX_DIR_SIZE: u64 = 4 << 30;
+
+// Scene files and resources loaded from them by md5 of the file, so scenes that differ
+// in some of the files share the rest.
+#[derive(Default)]
+pub struct ResourceCache {
+    // Files are also kept there if set, so they survive restarts.
+    dir: Option<PathBuf>,
+    files: Mutex<HashMap<String, Arc<Vec<u8>>>>,
+    resources: Mutex<HashMap<String, Arc<dyn Any + Send + Sync>>>,
+    // Md5s of the files used by every scene, entries of other files are evicted.
+    scene_files: Mutex<HashMap<String, HashSet<String>>>,
+}
+
+impl ResourceCache {
+    pub fn new(dir: Option<PathBuf>) -> ResourceCache {
+        ResourceCache {
+            dir,
+            ..Default::default()
+        }
+    }
+
+    // Files of shared resources aren't kept in memory, as the resources are.
+    pub async fn get_file(
+        &self,
+        md5: &str,
+        keep_in_memory: bool,
+        fetch: impl Future<Output = anyhow::Result<Vec<u8>>>,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        if let Some(data) = self.files.lock().unwrap().get(md5) {
+            return Ok(data.clone());
+        }
+
+        let path = self.dir.as_ref().map(|dir| dir.join(md5));
+        let stored = match &path {
+            Some(path) => tokio::fs::read(path).await.ok(),
+            None => None,
+        };
+        // Files corrupted on disk are fetched again.
+        let data = match stored.filter(|data| format!("{:x}", md5::compute(data)) == md5) {
+            Some(data) => data,
+            None => {
+                let data = fetch.await?;
+                if format!("{:x}", md5::compute(&data)) != md5 {
+                    anyhow::bail!("Fetched file doesn't match md5 {}", md5);
+                }
+                if let Some(path) = &path {
+                    Self::store(path, &data).await;
+                    self.trim_dir().await;
+                }
+                data
+            }
+        };
+
+        let data = Arc::new(data);
+        if keep_in_memory {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(md5.to_string(), data.clone());
+        }
+        Ok(data)
+    }
+
+    // Failing to store the file only makes it fetched again after restart.
+    async fn store(path: &Path, data: &[u8]) {
+        let result = async {
+            if let Some(dir) = path.parent() {
+                tokio::fs::create_dir_all(dir).await?;
+            }
+            tokio::fs::write(path, data).await
+        }
+        .await
+        .with_context(|| format!("Failed to cache file {}", path.display()));
+        if let Err(err) = result {
+            println!("{:#}", err);
+        }
+    }
+
+    // Removes files not used by any scene first, the oldest first, until the cache
+    // directory fits in MAX_DIR_SIZE.
+    async fn trim_dir(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        let used_files = self.used_files();
+        let result = async {
+            let mut files = vec![];
+            let mut dir_size = 0;
+            let mut entries = tokio::fs::read_dir(dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                if !metadata.is_file() {
+                    continue;
+                }
+                dir_size += metadata.len();
+                let used = entry
+                    .file_name()
+                    .to_str()
+                    .is_some_and(|md5| used_files.contains(md5));
+                files.push((used, metadata.modified()?, metadata.len(), entry.path()));
+            }
+
+            files.sort();
+            for (_, _, size, path) in files {
+                if dir_size <= MAX_DIR_SIZE {
+                    break;
+                }
+                tokio::fs::remove_file(&path).await?;
+                dir_size -= size;
+            }
+            std::io::Result::Ok(())
+        }
+        .await
+        .with_context(|| format!("Failed to trim cache directory {}", dir.display()));
+        if let Err(err) = result {
+            println!("{:#}", err);
+        }
+    }
+
+    fn used_files(&self) -> HashSet<String> {
+        self.scene_files
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    // Keeps the file and resources loaded from it while the scene is retained.
+    pub fn add_scene_file(&self, scene_md5: &str, md5: &str) {
+        self.scene_files
+            .lock()
+            .unwrap()
+            .entry(scene_md5.to_string())
+            .or_default()
+            .insert(md5.to_string());
+    }
+
+    // Evicts files and resources used only by other scenes from memory.
+    pub fn retain_scenes(&self, scene_md5s: &[&str]) {
+        self.scene_files
+            .lock()
+            .unwrap()
+            .retain(|scene_md5, _| scene_md5s.contains(&scene_md5.as_str()));
+        let used_files = self.used_files();
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|md5, _| used_files.contains(md5));
+        self.resources
+            .lock()
+            .unwrap()
+            .retain(|md5, _| used_files.contains(md5));
+    }
+
+    pub fn get_resource<T: Send + Sync + 'static>(&self, md5: &str) -> Option<Arc<T>> {
+        let resource = self.resources.lock().unwrap().get(md5)?.clone();
+        resource.downcast().ok()
+    }
+
+    pub fn insert_resource<T: Send + Sync + 'static>(&self, md5: &str, resource: Arc<T>) {
+        self.resources
+            .lock()
+            .unwrap()
+            .insert(md5.to_string(), resource);
+    }
+}
@@ -0,0 +1,62 @@
+use math::Vec2;
+
+use super::{Sampler, hash, permutation_element, to_unit_float};
+
+const PRIMES: [u32; 64] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131, 137, 139, 149, 151, 157, 163, 167, 173, 179, 181, 191, 193,
+    197, 199, 211, 223, 227, 229, 233, 239, 241, 251, 257, 263, 269, 271, 277, 281, 283, 293, 307,
+    311,
+];
+
+// Halton sequence with digits of every dimension Owen-scrambled per pixel.
+// Dimensions past the prime table are random.
+pub struct HaltonSampler {
+    pixel_seed: u64,
+    sample_id: u64,
+    dimension: usize,
+}
+
+impl HaltonSampler {
+    pub fn new(pixel_seed: u64, sample_id: usize) -> HaltonSampler {
+        HaltonSampler {
+            pixel_seed,
+            sample_id: sample_id as u64,
+            dimension: 0,
+        }
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn get_1d(&mut self) -> f32 {
+        let dimension_hash = hash(&[self.pixel_seed, self.dimension as u64]);
+        let value = match PRIMES.get(self.dimension) {
+            Some(&base) => scrambled_radical_inverse(self.sample_id, base, dimension_hash),
+            None => to_unit_float(hash(&[dimension_hash, self.sample_id]) as u32),
+        };
+        self.dimension += 1;
+        value.min(1.0 - f32::EPSILON / 2.0)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        Vec2::new(self.get_1d(), self.get_1d())
+    }
+}
+
+// Mirrors digits of the index in the given base around the radix point, every digit is
+// permuted depending on the digits before it.
+fn scrambled_radical_inverse(mut index: u64, base: u32, seed: u64) -> f32 {
+    let inv_base = 1.0 / base as f32;
+    let mut inv_base_power = 1.0;
+    let mut reversed_digits = 0u64;
+    // Trailing zero digits are scrambled too, until they are below float precision.
+    while 1.0 - inv_base_power < 1.0 {
+        let digit = (index % base as u64) as u32;
+        let digit_seed = hash(&[seed ^ reversed_digits]) as u32;
+        let digit = permutation_element(digit, base, digit_seed);
+        reversed_digits = reversed_digits * base as u64 + digit as u64;
+        inv_base_power *= inv_base;
+        index /= base as u64;
+    }
+    inv_base_power * reversed_digits as f32
+}
@@ -0,0 +1,27 @@
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+use math::Vec2;
+
+use super::{Sampler, hash};
+
+pub struct IndependentSampler {
+    rng: StdRng,
+}
+
+impl IndependentSampler {
+    pub fn new(pixel_seed: u64, sample_id: usize) -> IndependentSampler {
+        IndependentSampler {
+            rng: StdRng::seed_from_u64(hash(&[pixel_seed, sample_id as u64])),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn get_1d(&mut self) -> f32 {
+        self.rng.gen_range(0.0..1.0)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        Vec2::new(self.get_1d(), self.get_1d())
+    }
+}
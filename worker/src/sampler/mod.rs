@@ -0,0 +1,107 @@
+use serde::{Deserialize, Serialize};
+
+use math::{UVec2, Vec2};
+
+mod halton;
+mod independent;
+mod sobol;
+mod stratified;
+
+use halton::HaltonSampler;
+use independent::IndependentSampler;
+use sobol::SobolSampler;
+use stratified::StratifiedSampler;
+
+// Random numbers for a single sample of a single pixel. Every call to get_1d/get_2d
+// consumes the next dimension, so callers must request dimensions in the same order
+// for every sample. Values depend only on the seed, the pixel and the sample index,
+// so renders don't depend on the work order.
+pub trait Sampler {
+    fn get_1d(&mut self) -> f32;
+    fn get_2d(&mut self) -> Vec2;
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerType {
+    #[default]
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerType {
+    pub fn create(
+        &self,
+        seed: u64,
+        pixel: UVec2,
+        sample_id: usize,
+        sample_count: usize,
+    ) -> Box<dyn Sampler> {
+        let pixel_seed = hash(&[seed, pixel.x as u64, pixel.y as u64]);
+        match self {
+            SamplerType::Independent => Box::new(IndependentSampler::new(pixel_seed, sample_id)),
+            SamplerType::Stratified => {
+                Box::new(StratifiedSampler::new(pixel_seed, sample_id, sample_count))
+            }
+            SamplerType::Halton => Box::new(HaltonSampler::new(pixel_seed, sample_id)),
+            SamplerType::Sobol => Box::new(SobolSampler::new(pixel_seed, sample_id)),
+        }
+    }
+}
+
+fn hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .fold(splitmix64(0), |hash, &value| splitmix64(hash ^ value))
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+// Maps high bits to [0, 1).
+fn to_unit_float(value: u32) -> f32 {
+    (value >> 8) as f32 * (1.0 / (1u32 << 24) as f32)
+}
+
+// Element i of a random permutation of [0, length) defined by the seed,
+// from "Correlated Multi-Jittered Sampling" by A. Kensler.
+fn permutation_element(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut mask = length.wrapping_sub(1);
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+
+    i.wrapping_add(seed) % length
+}
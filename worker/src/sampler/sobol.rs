@@ -0,0 +1,76 @@
+use math::Vec2;
+
+use super::{Sampler, hash, to_unit_float};
+
+// Owen-scrambled Sobol sequence, from "Practical Hash-based Owen Scrambling" by B. Burley.
+// Only the first two Sobol dimensions are used, higher dimensions are padded
+// with independently shuffled and scrambled copies of them.
+pub struct SobolSampler {
+    pixel_seed: u64,
+    sample_id: u32,
+    dimension: u64,
+}
+
+impl SobolSampler {
+    pub fn new(pixel_seed: u64, sample_id: usize) -> SobolSampler {
+        SobolSampler {
+            pixel_seed,
+            sample_id: sample_id as u32,
+            dimension: 0,
+        }
+    }
+
+    // Returns the shuffled sample index and scrambling seeds for the next dimension.
+    fn next_dimension(&mut self) -> (u32, u64) {
+        let dimension_hash = hash(&[self.pixel_seed, self.dimension]);
+        self.dimension += 1;
+        let index = nested_uniform_scramble(self.sample_id, dimension_hash as u32);
+        (index, hash(&[dimension_hash]))
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn get_1d(&mut self) -> f32 {
+        let (index, seed) = self.next_dimension();
+        to_unit_float(nested_uniform_scramble(index.reverse_bits(), seed as u32))
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        // Both coordinates share the index, so the 2D stratification of the sequence is kept.
+        let (index, seed) = self.next_dimension();
+        Vec2::new(
+            to_unit_float(nested_uniform_scramble(index.reverse_bits(), seed as u32)),
+            to_unit_float(nested_uniform_scramble(
+                sobol_second_dimension(index),
+                (seed >> 32) as u32,
+            )),
+        )
+    }
+}
+
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+// Owen scrambling, every bit is flipped depending on the bits above it.
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    laine_karras_permutation(value.reverse_bits(), seed).reverse_bits()
+}
+
+fn laine_karras_permutation(mut value: u32, seed: u32) -> u32 {
+    value = value.wrapping_add(seed);
+    value ^= value.wrapping_mul(0x6c50b47c);
+    value ^= value.wrapping_mul(0xb82f1e52);
+    value ^= value.wrapping_mul(0xc7afe638);
+    value ^= value.wrapping_mul(0x8d22f6e6);
+    value
+}
@@ -0,0 +1,56 @@
+use math::Vec2;
+
+use super::{Sampler, hash, permutation_element, to_unit_float};
+
+// Every dimension is split into sample_count strata, each sample of the pixel gets
+// its own stratum in a random order, jittered inside it.
+pub struct StratifiedSampler {
+    pixel_seed: u64,
+    sample_id: u32,
+    sample_count: u32,
+    dimension: u64,
+}
+
+impl StratifiedSampler {
+    pub fn new(pixel_seed: u64, sample_id: usize, sample_count: usize) -> StratifiedSampler {
+        let sample_count = sample_count.max(1) as u32;
+        StratifiedSampler {
+            pixel_seed,
+            sample_id: sample_id as u32 % sample_count,
+            sample_count,
+            dimension: 0,
+        }
+    }
+
+    // Returns the stratum of the current sample and two jitter values.
+    fn next_stratum(&mut self, strata_count: u32) -> (u32, u32, u32) {
+        let dimension_seed = hash(&[self.pixel_seed, self.dimension]);
+        self.dimension += 1;
+
+        let stratum = permutation_element(self.sample_id, strata_count, dimension_seed as u32);
+        let jitter = hash(&[dimension_seed, self.sample_id as u64]);
+        (stratum, jitter as u32, (jitter >> 32) as u32)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn get_1d(&mut self) -> f32 {
+        let (stratum, jitter, _) = self.next_stratum(self.sample_count);
+        jittered(stratum, jitter, self.sample_count)
+    }
+
+    fn get_2d(&mut self) -> Vec2 {
+        // Square grid can have more cells than samples, each sample still gets a distinct cell.
+        let side = (self.sample_count as f32).sqrt().ceil() as u32;
+        let (cell, jitter_x, jitter_y) = self.next_stratum(side * side);
+        Vec2::new(
+            jittered(cell % side, jitter_x, side),
+            jittered(cell / side, jitter_y, side),
+        )
+    }
+}
+
+fn jittered(stratum: u32, jitter: u32, strata_count: u32) -> f32 {
+    // Rounding can push the last stratum to 1.0.
+    ((stratum as f32 + to_unit_float(jitter)) / strata_count as f32).min(1.0 - f32::EPSILON / 2.0)
+}
@@ -0,0 +1,259 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use math::{Mat3, Vec2, Vec3};
+
+use super::{
+    Scene,
+    resource::{ResourceId, ResourceIdUninit, image::Image},
+};
+
+pub type EnvironmentSourceUninit = EnvironmentSourceGeneric<ResourceIdUninit>;
+pub type EnvironmentSource = EnvironmentSourceGeneric<ResourceId>;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum EnvironmentSourceGeneric<I> {
+    Constant {
+        color: Vec3,
+    },
+    Gradient {
+        zenith: Vec3,
+        horizon: Vec3,
+        ground: Vec3,
+    },
+    // Equirectangular image, its center looks along -z.
+    Image {
+        path: I,
+    },
+}
+
+impl Default for EnvironmentSourceUninit {
+    fn default() -> EnvironmentSourceUninit {
+        EnvironmentSourceUninit::Constant {
+            color: Vec3::default(),
+        }
+    }
+}
+
+pub struct Environment {
+    source: EnvironmentSource,
+    intensity: f32,
+    rotation: Mat3,
+    rotation_inverse: Mat3,
+    // Only image environments are importance sampled, others are sampled uniformly.
+    distribution: Option<Distribution2D>,
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new(
+            EnvironmentSource::Constant {
+                color: Vec3::default(),
+            },
+            1.0,
+            Mat3::identity(),
+            &[],
+        )
+    }
+}
+
+impl Environment {
+    pub fn new(
+        source: EnvironmentSource,
+        intensity: f32,
+        rotation: Mat3,
+        images: &[Arc<Image>],
+    ) -> Environment {
+        let distribution = match &source {
+            EnvironmentSource::Image { path } => {
+                let image = &images[*path];
+                let (width, height) = (image.width() as usize, image.height() as usize);
+                let weights: Vec<_> = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let pixel = image.get_pixel(Vec2::new(x as f32, y as f32));
+                        // Rows near the poles cover smaller solid angle.
+                        let theta_sin = (math::PI * (y as f32 + 0.5) / height as f32).sin();
+                        luminance(pixel) * theta_sin
+                    })
+                    .collect();
+                Some(Distribution2D::new(&weights, width, height))
+            }
+            _ => None,
+        };
+
+        Environment {
+            source,
+            intensity,
+            rotation_inverse: rotation.transposed(),
+            rotation,
+            distribution,
+        }
+    }
+
+    pub fn is_black(&self) -> bool {
+        match &self.source {
+            EnvironmentSource::Constant { color } => self.intensity * luminance(*color) <= 0.0,
+            EnvironmentSource::Gradient { .. } => self.intensity <= 0.0,
+            EnvironmentSource::Image { .. } => {
+                self.intensity <= 0.0
+                    || self
+                        .distribution
+                        .as_ref()
+                        .is_none_or(|distribution| distribution.total_weight <= 0.0)
+            }
+        }
+    }
+
+    pub fn radiance(&self, scene: &Scene, dir: Vec3) -> Vec3 {
+        let dir = &self.rotation_inverse * dir;
+        let radiance = match &self.source {
+            EnvironmentSource::Constant { color } => *color,
+            EnvironmentSource::Gradient {
+                zenith,
+                horizon,
+                ground,
+            } => {
+                if dir.y >= 0.0 {
+                    *horizon * (1.0 - dir.y) + *zenith * dir.y
+                } else {
+                    *horizon * (1.0 + dir.y) - *ground * dir.y
+                }
+            }
+            EnvironmentSource::Image { path } => {
+                let image = &scene.images[*path];
+                let (x, y) = Self::dir_to_pixel(dir, image.width(), image.height());
+                image.get_pixel(Vec2::new(x as f32, y as f32))
+            }
+        };
+        radiance * self.intensity
+    }
+
+    // Returns direction and its solid angle pdf.
+    pub fn sample(&self, rand_0: f32, rand_1: f32) -> (Vec3, f32) {
+        let Some(distribution) = &self.distribution else {
+            let dir = Vec3::random_on_unit_sphere(rand_0, rand_1);
+            return (dir, 0.25 * math::INV_PI);
+        };
+
+        let (uv, uv_pdf) = distribution.sample(rand_0, rand_1);
+        let phi = (uv.x - 0.5) * 2.0 * math::PI;
+        let theta = uv.y * math::PI;
+        let theta_sin = theta.sin();
+        let dir = Vec3::new(theta_sin * phi.sin(), theta.cos(), -theta_sin * phi.cos());
+
+        let pdf = uv_pdf / (2.0 * math::PI * math::PI * theta_sin);
+        (&self.rotation * dir, pdf)
+    }
+
+    pub fn pdf(&self, dir: Vec3) -> f32 {
+        let Some(distribution) = &self.distribution else {
+            return 0.25 * math::INV_PI;
+        };
+
+        let dir = &self.rotation_inverse * dir;
+        let (x, y) = Self::dir_to_pixel(dir, distribution.width as u32, distribution.height as u32);
+        let theta_sin = (1.0 - dir.y * dir.y).max(0.0).sqrt();
+        if theta_sin <= 0.0 {
+            return 0.0;
+        }
+        distribution.pdf(x, y) / (2.0 * math::PI * math::PI * theta_sin)
+    }
+
+    fn dir_to_pixel(dir: Vec3, width: u32, height: u32) -> (usize, usize) {
+        let u = f32::atan2(dir.x, -dir.z) * 0.5 * math::INV_PI + 0.5;
+        let v = dir.y.clamp(-1.0, 1.0).acos() * math::INV_PI;
+        let x = ((u * width as f32) as usize).min(width as usize - 1);
+        let y = ((v * height as f32) as usize).min(height as usize - 1);
+        (x, y)
+    }
+}
+
+fn luminance(color: Vec3) -> f32 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Piecewise constant distribution over [0, 1]^2.
+struct Distribution2D {
+    width: usize,
+    height: usize,
+    // Cdf of columns inside every row, normalized per row.
+    conditional_cdf: Vec<f32>,
+    marginal_cdf: Vec<f32>,
+    weights: Vec<f32>,
+    total_weight: f32,
+}
+
+impl Distribution2D {
+    fn new(weights: &[f32], width: usize, height: usize) -> Distribution2D {
+        let mut conditional_cdf = Vec::with_capacity(width * height);
+        let mut marginal_cdf = Vec::with_capacity(height);
+        let mut total_weight = 0.0;
+        for row in weights.chunks_exact(width) {
+            let row_weight: f32 = row.iter().sum();
+            let mut sum = 0.0;
+            for weight in row {
+                sum += weight;
+                conditional_cdf.push(if row_weight > 0.0 {
+                    sum / row_weight
+                } else {
+                    1.0
+                });
+            }
+            total_weight += row_weight;
+            marginal_cdf.push(total_weight);
+        }
+        if total_weight > 0.0 {
+            for cdf in &mut marginal_cdf {
+                *cdf /= total_weight;
+            }
+        }
+
+        Distribution2D {
+            width,
+            height,
+            conditional_cdf,
+            marginal_cdf,
+            weights: weights.to_vec(),
+            total_weight,
+        }
+    }
+
+    // Returns index and position inside the bin remapped to [0, 1).
+    fn sample_cdf(cdf: &[f32], rand: f32) -> (usize, f32) {
+        let id = cdf
+            .partition_point(|&value| value <= rand)
+            .min(cdf.len() - 1);
+        let start = if id == 0 { 0.0 } else { cdf[id - 1] };
+        let size = cdf[id] - start;
+        let offset = if size > 0.0 {
+            ((rand - start) / size).clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+        (id, offset)
+    }
+
+    // Returns point and its pdf.
+    fn sample(&self, rand_0: f32, rand_1: f32) -> (Vec2, f32) {
+        let (y, y_offset) = Self::sample_cdf(&self.marginal_cdf, rand_0);
+        let row_cdf = &self.conditional_cdf[y * self.width..(y + 1) * self.width];
+        let (x, x_offset) = Self::sample_cdf(row_cdf, rand_1);
+
+        let point = Vec2::new(
+            (x as f32 + x_offset) / self.width as f32,
+            (y as f32 + y_offset) / self.height as f32,
+        );
+        (point, self.pdf(x, y))
+    }
+
+    fn pdf(&self, x: usize, y: usize) -> f32 {
+        if self.total_weight <= 0.0 {
+            return 0.0;
+        }
+        self.weights[x + y * self.width] / self.total_weight * (self.width * self.height) as f32
+    }
+}
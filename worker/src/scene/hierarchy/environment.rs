@@ -0,0 +1,105 @@
+use std::{collections::HashSet, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+use math::Mat3;
+
+use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
+use crate::{
+    aabb::Aabb,
+    ray::Ray,
+    renderer::cpu_renderer::{self, RayTraceResult},
+    scene::{
+        Scene,
+        environment::{
+            Environment, EnvironmentSource, EnvironmentSourceGeneric, EnvironmentSourceUninit,
+        },
+        resource::{ResourceId, ResourceIdUninit, ResourceType},
+    },
+};
+
+pub type EnvironmentNodeUnloaded = EnvironmentNodeGeneric<ResourceIdUninit>;
+pub type EnvironmentNode = EnvironmentNodeGeneric<ResourceId>;
+
+// Has no geometry, sets the environment that is visible when rays miss the scene.
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct EnvironmentNodeGeneric<I> {
+    pub source: EnvironmentSourceGeneric<I>,
+    pub intensity: f32,
+    pub rotation: Mat3,
+}
+
+impl Default for EnvironmentNodeUnloaded {
+    fn default() -> EnvironmentNodeUnloaded {
+        EnvironmentNodeUnloaded {
+            source: EnvironmentSourceUninit::default(),
+            intensity: 1.0,
+            rotation: Mat3::identity(),
+        }
+    }
+}
+
+#[typetag::serde(name = "environment")]
+impl SceneNodeUnloaded for EnvironmentNodeUnloaded {
+    fn collect_references(&self) -> HashSet<ResourceReferenceUninit> {
+        match &self.source {
+            EnvironmentSourceGeneric::Image { path } => HashSet::from([ResourceReferenceUninit {
+                path: path.clone(),
+                ty: ResourceType::Image,
+            }]),
+            _ => HashSet::new(),
+        }
+    }
+
+    fn init(self: Box<Self>, reference_replacer: &mut dyn ReferenceReplacer) -> Box<dyn SceneNode> {
+        let source = match self.source {
+            EnvironmentSourceGeneric::Constant { color } => EnvironmentSource::Constant { color },
+            EnvironmentSourceGeneric::Gradient {
+                zenith,
+                horizon,
+                ground,
+            } => EnvironmentSource::Gradient {
+                zenith,
+                horizon,
+                ground,
+            },
+            EnvironmentSourceGeneric::Image { path } => EnvironmentSource::Image {
+                path: reference_replacer
+                    .get_replacement(ResourceReferenceUninit {
+                        ty: ResourceType::Image,
+                        path,
+                    })
+                    .path,
+            },
+        };
+
+        Box::from(EnvironmentNode {
+            source,
+            intensity: self.intensity,
+            rotation: self.rotation,
+        })
+    }
+}
+
+impl SceneNode for EnvironmentNode {}
+
+impl cpu_renderer::SceneNode for EnvironmentNode {
+    fn trace_ray(&self, _: Arc<Scene>, _: &Ray) -> RayTraceResult {
+        RayTraceResult::void()
+    }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        Aabb::empty()
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        scene.environment = Environment::new(
+            self.source.clone(),
+            self.intensity,
+            self.rotation,
+            &scene.images,
+        );
+        Ok(())
+    }
+}
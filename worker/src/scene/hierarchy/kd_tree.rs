@@ -4,9 +4,13 @@ use serde::{Deserialize, Serialize};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
-    scene::{resource::ResourceType, Scene},
+    scene::{
+        Scene,
+        resource::{ResourceType, kd_tree},
+    },
 };
 
 pub type KdTreeUnloaded = KdTreeGeneric<String, Box<dyn SceneNodeUnloaded>>;
@@ -45,7 +49,47 @@ impl SceneNodeUnloaded for KdTreeUnloaded {
 impl SceneNode for KdTree {}
 
 impl cpu_renderer::SceneNode for KdTree {
-    fn trace_ray(&self, _: Arc<Scene>, _: &Ray) -> RayTraceResult {
-        todo!()
+    fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
+        scene.kd_trees[self.path].trace_ray(ray, |id, ray| {
+            self.child.trace_primitive(scene.clone(), ray, id)
+        })
+    }
+
+    fn bounds(&self, scene: &Scene) -> Aabb {
+        self.child.bounds(scene)
+    }
+
+    fn primitive_count(&self, scene: &Scene) -> usize {
+        self.child.primitive_count(scene)
+    }
+
+    fn primitive_bounds(&self, scene: &Scene, id: usize) -> Aabb {
+        self.child.primitive_bounds(scene, id)
+    }
+
+    fn trace_primitive(&self, scene: Arc<Scene>, ray: &Ray, id: usize) -> RayTraceResult {
+        self.child.trace_primitive(scene, ray, id)
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        self.child.prepare(scene)?;
+
+        let primitive_bounds: Vec<_> = (0..self.child.primitive_count(scene))
+            .map(|id| self.child.primitive_bounds(scene, id))
+            .collect();
+
+        // Nodes sharing the tree file would use the tree built over primitives of the other one.
+        let first_user = scene.prepared_kd_trees.insert(self.path);
+        if !scene.kd_trees[self.path].is_built_for(&primitive_bounds) {
+            if !first_user {
+                anyhow::bail!("Kd-tree file is shared by nodes with different children");
+            }
+            println!(
+                "Building kd-tree over {} primitives...",
+                primitive_bounds.len()
+            );
+            scene.kd_trees[self.path] = Arc::new(kd_tree::KdTree::build(&primitive_bounds));
+        }
+        Ok(())
     }
 }
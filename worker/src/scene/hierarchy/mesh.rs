@@ -4,11 +4,13 @@ use serde::{Deserialize, Serialize};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::{
-        resource::{ResourceId, ResourceIdUninit, ResourceType},
         Scene,
+        light::Light,
+        resource::{ResourceId, ResourceIdUninit, ResourceType},
     },
 };
 
@@ -19,6 +21,8 @@ pub type Mesh = MeshGeneric<ResourceId>;
 pub struct MeshGeneric<R> {
     pub path: R,
     pub material: R,
+    #[serde(skip)]
+    is_light: bool,
 }
 
 #[typetag::serde(name = "mesh")]
@@ -52,6 +56,7 @@ impl SceneNodeUnloaded for MeshUnloaded {
         Box::from(Mesh {
             path: path_replacement.path,
             material: material_replacement.path,
+            is_light: false,
         })
     }
 }
@@ -62,6 +67,36 @@ impl cpu_renderer::SceneNode for Mesh {
     fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
         let mut result = scene.meshes[self.path].trace_ray(scene.clone(), ray);
         result.material_id = self.material;
+        result.is_light = self.is_light;
+        result
+    }
+
+    fn bounds(&self, scene: &Scene) -> Aabb {
+        scene.meshes[self.path].bounds(scene)
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        self.is_light = scene.materials[self.material].is_emissive();
+        if self.is_light {
+            for vertices in scene.meshes[self.path].triangle_vertices() {
+                scene.lights.push(Light::triangle(vertices, self.material));
+            }
+        }
+        Ok(())
+    }
+
+    fn primitive_count(&self, scene: &Scene) -> usize {
+        scene.meshes[self.path].primitive_count(scene)
+    }
+
+    fn primitive_bounds(&self, scene: &Scene, id: usize) -> Aabb {
+        scene.meshes[self.path].primitive_bounds(scene, id)
+    }
+
+    fn trace_primitive(&self, scene: Arc<Scene>, ray: &Ray, id: usize) -> RayTraceResult {
+        let mut result = scene.meshes[self.path].trace_primitive(scene.clone(), ray, id);
+        result.material_id = self.material;
+        result.is_light = self.is_light;
         result
     }
 }
@@ -5,6 +5,7 @@ use crate::{
     scene::resource::{ReferenceReplacer, ResourceReferenceUninit},
 };
 
+pub mod environment;
 pub mod kd_tree;
 pub mod mesh;
 pub mod node_collection;
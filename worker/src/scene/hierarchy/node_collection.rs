@@ -4,6 +4,8 @@ use serde::{Deserialize, Serialize};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
+    bvh::Bvh,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::Scene,
@@ -15,6 +17,34 @@ pub type NodeCollection = NodeCollectionGeneric<Box<dyn SceneNode>>;
 #[derive(Deserialize, Serialize)]
 pub struct NodeCollectionGeneric<R> {
     pub children: Vec<R>,
+    // Id of the first primitive of each child.
+    #[serde(skip)]
+    primitive_offsets: Vec<usize>,
+    #[serde(skip)]
+    bvh: Bvh,
+}
+
+impl Default for NodeCollection {
+    fn default() -> NodeCollection {
+        NodeCollection {
+            children: vec![],
+            primitive_offsets: vec![],
+            bvh: Bvh::default(),
+        }
+    }
+}
+
+impl NodeCollection {
+    fn find_primitive(&self, id: usize) -> (&dyn SceneNode, usize) {
+        let child_id = self
+            .primitive_offsets
+            .partition_point(|&offset| offset <= id)
+            - 1;
+        (
+            self.children[child_id].as_ref(),
+            id - self.primitive_offsets[child_id],
+        )
+    }
 }
 
 #[typetag::serde(name = "node_collection")]
@@ -34,6 +64,8 @@ impl SceneNodeUnloaded for NodeCollectionUnloaded {
                 .into_iter()
                 .map(|child| child.init(reference_replacer))
                 .collect(),
+            primitive_offsets: vec![],
+            bvh: Bvh::default(),
         })
     }
 }
@@ -42,14 +74,47 @@ impl SceneNode for NodeCollection {}
 
 impl cpu_renderer::SceneNode for NodeCollection {
     fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
-        let mut result = RayTraceResult::void();
-        result.t = f32::INFINITY;
-        for child in &self.children {
-            let child_result = child.trace_ray(scene.clone(), ray);
-            if child_result.hit && child_result.t < result.t {
-                result = child_result;
-            }
+        self.bvh.trace_ray(ray, |id, ray| {
+            self.children[id].trace_ray(scene.clone(), ray)
+        })
+    }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn primitive_count(&self, scene: &Scene) -> usize {
+        self.children
+            .iter()
+            .map(|child| child.primitive_count(scene))
+            .sum()
+    }
+
+    fn primitive_bounds(&self, scene: &Scene, id: usize) -> Aabb {
+        let (child, id) = self.find_primitive(id);
+        child.primitive_bounds(scene, id)
+    }
+
+    fn trace_primitive(&self, scene: Arc<Scene>, ray: &Ray, id: usize) -> RayTraceResult {
+        let (child, id) = self.find_primitive(id);
+        child.trace_primitive(scene, ray, id)
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        let mut offset = 0;
+        self.primitive_offsets.clear();
+        for child in &mut self.children {
+            child.prepare(scene)?;
+            self.primitive_offsets.push(offset);
+            offset += child.primitive_count(scene);
         }
-        result
+
+        let child_bounds: Vec<_> = self
+            .children
+            .iter()
+            .map(|child| child.bounds(scene))
+            .collect();
+        self.bvh = Bvh::build(&child_bounds);
+        Ok(())
     }
 }
@@ -6,11 +6,12 @@ use math::{Vec2, Vec3};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::{
-        resource::{ResourceId, ResourceIdUninit, ResourceType},
         Scene,
+        resource::{ResourceId, ResourceIdUninit, ResourceType},
     },
 };
 
@@ -101,4 +102,8 @@ impl cpu_renderer::SceneNode for Plane {
 
         result
     }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        Aabb::infinite()
+    }
 }
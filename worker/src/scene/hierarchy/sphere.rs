@@ -6,11 +6,13 @@ use math::{Vec2, Vec3};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::{
-        resource::{ResourceId, ResourceIdUninit, ResourceType},
         Scene,
+        light::Light,
+        resource::{ResourceId, ResourceIdUninit, ResourceType},
     },
 };
 
@@ -26,6 +28,8 @@ pub struct SphereGeneric<R> {
     radius_sqr: f32,
 
     pub material: R,
+    #[serde(skip)]
+    is_light: bool,
 }
 
 #[typetag::serde(name = "sphere")]
@@ -50,6 +54,7 @@ impl SceneNodeUnloaded for SphereUnloaded {
             radius: self.radius,
             radius_sqr: self.radius * self.radius,
             material: material_replacement.path,
+            is_light: false,
         })
     }
 }
@@ -96,7 +101,23 @@ impl cpu_renderer::SceneNode for Sphere {
 
         result.hit = true;
         result.material_id = self.material;
+        result.is_light = self.is_light;
 
         result
     }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        let radius = Vec3::new_xyz(self.radius);
+        Aabb::new(self.center - radius, self.center + radius)
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        self.is_light = scene.materials[self.material].is_emissive();
+        if self.is_light {
+            scene
+                .lights
+                .push(Light::sphere(self.center, self.radius, self.material));
+        }
+        Ok(())
+    }
 }
@@ -6,6 +6,7 @@ use math::{Mat3, Mat4, Vec4};
 
 use super::{ReferenceReplacer, ResourceReferenceUninit, SceneNode, SceneNodeUnloaded};
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::Scene,
@@ -40,13 +41,8 @@ impl SceneNodeUnloaded for TransformUnloaded {
     }
 }
 
-impl SceneNode for Transform {}
-
-impl cpu_renderer::SceneNode for Transform {
-    fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
-        let new_ray = ray.apply_transform(&self.matrix_inverse);
-        let result = self.child.trace_ray(scene, &new_ray);
-
+impl Transform {
+    fn transform_result(&self, ray: &Ray, result: RayTraceResult) -> RayTraceResult {
         if result.hit {
             let point = &self.matrix * Vec4::from_vec3(result.point);
             let t = (point - ray.source).length();
@@ -55,13 +51,51 @@ impl cpu_renderer::SceneNode for Transform {
                 hit: result.hit,
                 hit_inside: result.hit_inside,
                 point,
-                normal: &self.normal_matrix * result.normal,
+                normal: (&self.normal_matrix * result.normal).normalized(),
                 uv: result.uv,
                 t,
                 material_id: result.material_id,
+                is_light: result.is_light,
             }
         } else {
             result
         }
     }
 }
+
+impl SceneNode for Transform {}
+
+impl cpu_renderer::SceneNode for Transform {
+    fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
+        let new_ray = ray.apply_transform(&self.matrix_inverse);
+        let result = self.child.trace_ray(scene, &new_ray);
+        self.transform_result(ray, result)
+    }
+
+    fn bounds(&self, scene: &Scene) -> Aabb {
+        self.child.bounds(scene).transform(&self.matrix)
+    }
+
+    fn primitive_count(&self, scene: &Scene) -> usize {
+        self.child.primitive_count(scene)
+    }
+
+    fn primitive_bounds(&self, scene: &Scene, id: usize) -> Aabb {
+        self.child
+            .primitive_bounds(scene, id)
+            .transform(&self.matrix)
+    }
+
+    fn trace_primitive(&self, scene: Arc<Scene>, ray: &Ray, id: usize) -> RayTraceResult {
+        let new_ray = ray.apply_transform(&self.matrix_inverse);
+        let result = self.child.trace_primitive(scene, &new_ray, id);
+        self.transform_result(ray, result)
+    }
+
+    fn prepare(&mut self, scene: &mut Scene) -> anyhow::Result<()> {
+        let first_light = scene.lights.len();
+        self.child.prepare(scene)?;
+        scene.lights.transform(first_light, &self.matrix);
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+use math::{Mat4, Vec3, Vec4};
+
+use crate::renderer::cpu_renderer::RayTraceResult;
+
+enum LightShape {
+    Sphere { center: Vec3, radius: f32 },
+    Triangle { vertices: [Vec3; 3] },
+}
+
+pub struct Light {
+    shape: LightShape,
+    material_id: usize,
+}
+
+pub struct LightSample {
+    pub direction: Vec3,
+    pub distance: f32,
+    // Solid angle pdf.
+    pub pdf: f32,
+    // Point on the light as if it was hit by a ray in the sampled direction.
+    pub trace_result: RayTraceResult,
+}
+
+impl Light {
+    pub fn sphere(center: Vec3, radius: f32, material_id: usize) -> Light {
+        Light {
+            shape: LightShape::Sphere { center, radius },
+            material_id,
+        }
+    }
+
+    pub fn triangle(vertices: [Vec3; 3], material_id: usize) -> Light {
+        Light {
+            shape: LightShape::Triangle { vertices },
+            material_id,
+        }
+    }
+
+    fn transform(&mut self, matrix: &Mat4) {
+        match &mut self.shape {
+            LightShape::Sphere { center, radius } => {
+                // TODO: Non-uniform scale turns sphere into ellipsoid, it's sampled as a sphere.
+                let surface_point =
+                    matrix * Vec4::from_vec3(*center + Vec3::new(*radius, 0.0, 0.0));
+                *center = matrix * Vec4::from_vec3(*center);
+                *radius = (surface_point - *center).length();
+            }
+            LightShape::Triangle { vertices } => {
+                for vertex in vertices {
+                    *vertex = matrix * Vec4::from_vec3(*vertex);
+                }
+            }
+        }
+    }
+
+    fn area(&self) -> f32 {
+        match &self.shape {
+            LightShape::Sphere { radius, .. } => 4.0 * math::PI * radius * radius,
+            LightShape::Triangle { vertices } => {
+                (vertices[1] - vertices[0])
+                    .cross(vertices[2] - vertices[0])
+                    .length()
+                    * 0.5
+            }
+        }
+    }
+
+    // Uniformly samples point on the surface, returns the point and the normal.
+    fn sample_point(&self, rand_0: f32, rand_1: f32) -> (Vec3, Vec3) {
+        match &self.shape {
+            LightShape::Sphere { center, radius } => {
+                let normal = Vec3::random_on_unit_sphere(rand_0, rand_1);
+                (*center + normal * *radius, normal)
+            }
+            LightShape::Triangle { vertices } => {
+                let rand_0_sqrt = rand_0.sqrt();
+                let u = 1.0 - rand_0_sqrt;
+                let v = rand_1 * rand_0_sqrt;
+                let point = vertices[0] * (1.0 - u - v) + vertices[1] * u + vertices[2] * v;
+                let normal = (vertices[1] - vertices[0])
+                    .cross(vertices[2] - vertices[0])
+                    .normalized();
+                (point, normal)
+            }
+        }
+    }
+}
+
+// Lights are selected proportionally to their area, so every point on every light
+// is sampled with the same area pdf.
+#[derive(Default)]
+pub struct Lights {
+    lights: Vec<Light>,
+    area_cdf: Vec<f32>,
+    total_area: f32,
+}
+
+impl Lights {
+    pub fn push(&mut self, light: Light) {
+        self.lights.push(light);
+    }
+
+    pub fn len(&self) -> usize {
+        self.lights.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lights.is_empty()
+    }
+
+    // Lights with zero area can't be sampled.
+    pub fn has_samplable_area(&self) -> bool {
+        self.total_area > 0.0
+    }
+
+    // Transforms all the lights pushed after the first one.
+    pub fn transform(&mut self, first: usize, matrix: &Mat4) {
+        for light in &mut self.lights[first..] {
+            light.transform(matrix);
+        }
+    }
+
+    // Called once all the lights are pushed.
+    pub fn build_distribution(&mut self) {
+        self.area_cdf.clear();
+        self.total_area = 0.0;
+        for light in &self.lights {
+            self.total_area += light.area();
+            self.area_cdf.push(self.total_area);
+        }
+    }
+
+    pub fn sample(&self, point: Vec3, rand: [f32; 3]) -> Option<LightSample> {
+        if !self.has_samplable_area() {
+            return None;
+        }
+
+        let area = rand[0] * self.total_area;
+        let light_id = self
+            .area_cdf
+            .partition_point(|&cdf| cdf <= area)
+            .min(self.lights.len() - 1);
+        let light = &self.lights[light_id];
+
+        let (light_point, light_normal) = light.sample_point(rand[1], rand[2]);
+        let to_light = light_point - point;
+        let distance = to_light.length();
+        let direction = to_light / distance;
+
+        let pdf = self.pdf(distance, direction.dot(light_normal));
+        if !pdf.is_finite() {
+            return None;
+        }
+
+        let mut trace_result = RayTraceResult::void();
+        trace_result.hit = true;
+        trace_result.is_light = true;
+        trace_result.point = light_point;
+        trace_result.normal = if direction.dot(light_normal) > 0.0 {
+            light_normal * -1.0
+        } else {
+            light_normal
+        };
+        trace_result.t = distance;
+        trace_result.material_id = light.material_id;
+
+        Some(LightSample {
+            direction,
+            distance,
+            pdf,
+            trace_result,
+        })
+    }
+
+    // Solid angle pdf of sampling a point at the distance with the given cosine between
+    // the direction to it and its normal.
+    pub fn pdf(&self, distance: f32, light_cos: f32) -> f32 {
+        distance * distance / (light_cos.abs() * self.total_area)
+    }
+}
@@ -1,16 +1,27 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use anyhow::Context;
 
+pub mod environment;
 pub mod hierarchy;
+pub mod light;
 pub mod resource;
 
-use crate::file_store::FileStore;
+use crate::{file_store::FileStore, resource_cache::ResourceCache};
+use environment::Environment;
+use light::Lights;
 use resource::ReferenceMapping;
 
 use self::{
-    hierarchy::{SceneNode, SceneNodeUnloaded},
+    hierarchy::{SceneNode, SceneNodeUnloaded, node_collection::NodeCollection},
     resource::{
-        ReferenceReplacer, Resource, ResourceReferenceUninit, ResourceType,
+        ReferenceReplacer, Resource, ResourceId, ResourceIdUninit, ResourceReferenceUninit,
+        ResourceType,
         image::Image,
+        kd_tree::KdTree,
         material::{BoxedMaterial, Material},
         mesh::{Mesh, MeshUninit},
     },
@@ -19,12 +30,13 @@ use self::{
 impl Resource for SceneHierarchyUninit {
     type Initialized = Box<dyn SceneNode>;
 
-    fn load(data: &[u8]) -> Self
+    fn load(data: &[u8]) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        let data = String::from_utf8(data.to_vec()).unwrap();
-        SceneHierarchyUninit(serde_json::de::from_str(&data).unwrap())
+        let data = std::str::from_utf8(data).context("Scene file isn't valid UTF-8")?;
+        let hierarchy = serde_json::de::from_str(data).context("Incorrect scene format")?;
+        Ok(SceneHierarchyUninit(hierarchy))
     }
 
     fn collect_references(&self) -> HashSet<ResourceReferenceUninit> {
@@ -41,8 +53,126 @@ pub struct SceneHierarchyUninit(Box<dyn SceneNodeUnloaded>);
 pub struct Scene {
     pub hierarchy: Box<dyn SceneNode>,
     pub materials: Vec<Box<dyn Material>>,
-    pub meshes: Vec<Mesh>,
-    pub images: Vec<Image>,
+    pub meshes: Vec<Arc<Mesh>>,
+    pub images: Vec<Arc<Image>>,
+    pub kd_trees: Vec<Arc<KdTree>>,
+    pub lights: Lights,
+    pub environment: Environment,
+    // Kd-trees used by nodes that are already prepared.
+    prepared_kd_trees: HashSet<usize>,
+}
+
+// Loads the scene and returns contents of all the kd-tree files it references,
+// building the trees that are missing or outdated.
+// Files are fetched along with the type of the resource they contain, none for the scene itself.
+pub async fn build_kd_trees<F: Future<Output = anyhow::Result<Vec<u8>>>>(
+    scene_path: &str,
+    fetch_file: impl Fn(ResourceIdUninit, Option<ResourceType>) -> F,
+) -> anyhow::Result<Vec<(ResourceIdUninit, Vec<u8>)>> {
+    let (scene, kd_tree_paths) = Scene::load_with(&UncachedFiles(fetch_file), scene_path).await?;
+    Ok(kd_tree_paths
+        .into_iter()
+        .map(|(id, path)| (path, scene.kd_trees[id].to_bytes()))
+        .collect())
+}
+
+// Source of the files a scene is loaded from.
+trait SceneFiles {
+    async fn fetch_file(
+        &self,
+        path: &str,
+        resource_type: Option<ResourceType>,
+    ) -> anyhow::Result<Arc<Vec<u8>>>;
+    // Resource loaded from the file, can be shared with other scenes.
+    async fn load_shared<T: Send + Sync + 'static>(
+        &self,
+        path: &str,
+        resource_type: ResourceType,
+        load: impl FnOnce(&[u8]) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Arc<T>>;
+}
+
+struct UncachedFiles<F>(F);
+
+impl<F, Fut> SceneFiles for UncachedFiles<F>
+where
+    F: Fn(ResourceIdUninit, Option<ResourceType>) -> Fut,
+    Fut: Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    async fn fetch_file(
+        &self,
+        path: &str,
+        resource_type: Option<ResourceType>,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        Ok(Arc::new((self.0)(path.to_string(), resource_type).await?))
+    }
+
+    async fn load_shared<T: Send + Sync + 'static>(
+        &self,
+        path: &str,
+        resource_type: ResourceType,
+        load: impl FnOnce(&[u8]) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Arc<T>> {
+        let data = self.fetch_file(path, Some(resource_type)).await?;
+        Ok(Arc::new(load(&data)?))
+    }
+}
+
+struct CachedFiles<'a, S: ?Sized> {
+    file_store: &'a S,
+    cache: &'a ResourceCache,
+    scene_md5: &'a str,
+}
+
+impl<S: FileStore + ?Sized> SceneFiles for CachedFiles<'_, S> {
+    async fn fetch_file(
+        &self,
+        path: &str,
+        _resource_type: Option<ResourceType>,
+    ) -> anyhow::Result<Arc<Vec<u8>>> {
+        let fetch = self.file_store.fetch_file(self.scene_md5, path);
+        match self.file_store.file_md5(self.scene_md5, path).await? {
+            Some(md5) => {
+                self.cache.add_scene_file(self.scene_md5, &md5);
+                self.cache.get_file(&md5, true, fetch).await
+            }
+            // Stores without md5s are cheap to read from.
+            None => Ok(Arc::new(fetch.await?)),
+        }
+    }
+
+    async fn load_shared<T: Send + Sync + 'static>(
+        &self,
+        path: &str,
+        _resource_type: ResourceType,
+        load: impl FnOnce(&[u8]) -> anyhow::Result<T>,
+    ) -> anyhow::Result<Arc<T>> {
+        let fetch = self.file_store.fetch_file(self.scene_md5, path);
+        let (md5, data) = match self.file_store.file_md5(self.scene_md5, path).await? {
+            Some(md5) => {
+                self.cache.add_scene_file(self.scene_md5, &md5);
+                if let Some(resource) = self.cache.get_resource(&md5) {
+                    return Ok(resource);
+                }
+                let data = self.cache.get_file(&md5, false, fetch).await?;
+                (md5, data)
+            }
+            // Resources are still shared by md5 of the fetched file.
+            None => {
+                let data = fetch.await?;
+                let md5 = format!("{:x}", md5::compute(&data));
+                self.cache.add_scene_file(self.scene_md5, &md5);
+                if let Some(resource) = self.cache.get_resource(&md5) {
+                    return Ok(resource);
+                }
+                (md5, Arc::new(data))
+            }
+        };
+
+        let resource = Arc::new(load(&data)?);
+        self.cache.insert_resource(&md5, resource.clone());
+        Ok(resource)
+    }
 }
 
 impl Scene {
@@ -52,20 +182,43 @@ impl Scene {
             materials: vec![],
             meshes: vec![],
             images: vec![],
+            kd_trees: vec![],
+            lights: Lights::default(),
+            environment: Environment::default(),
+            prepared_kd_trees: HashSet::new(),
         }
     }
 
-    pub async fn load(file_store: &FileStore, scene_path: &str) -> Scene {
-        let scene_data = file_store.fetch_file(scene_path).await;
-        let scene_data = String::from_utf8(scene_data).unwrap();
+    // Only files missing in the cache are fetched.
+    pub async fn load<S: FileStore + ?Sized>(
+        file_store: &S,
+        cache: &ResourceCache,
+        scene_md5: &str,
+        scene_path: &str,
+    ) -> anyhow::Result<Scene> {
+        let files = CachedFiles {
+            file_store,
+            cache,
+            scene_md5,
+        };
+        Ok(Self::load_with(&files, scene_path).await?.0)
+    }
 
-        let hierarchy: Box<dyn SceneNodeUnloaded> = serde_json::de::from_str(&scene_data).unwrap();
+    async fn load_with(
+        files: &impl SceneFiles,
+        scene_path: &str,
+    ) -> anyhow::Result<(Scene, HashMap<ResourceId, ResourceIdUninit>)> {
+        let scene_data = files.fetch_file(scene_path, None).await?;
+        let hierarchy = SceneHierarchyUninit::load(&scene_data)
+            .with_context(|| format!("Failed to load scene {}", scene_path))?;
         let mut references = ReferenceMapping::default();
         let hierarchy = hierarchy.init(&mut references);
 
         let mut loaded_materials: HashMap<usize, Box<dyn Material>> = HashMap::new();
-        let mut loaded_meshes: HashMap<usize, Mesh> = HashMap::new();
-        let mut loaded_images: HashMap<usize, Image> = HashMap::new();
+        let mut loaded_meshes: HashMap<usize, Arc<Mesh>> = HashMap::new();
+        let mut loaded_images: HashMap<usize, Arc<Image>> = HashMap::new();
+        let mut loaded_kd_trees: HashMap<usize, Arc<KdTree>> = HashMap::new();
+        let mut kd_tree_paths = HashMap::new();
 
         let mut scene = Scene::new(hierarchy);
         loop {
@@ -75,24 +228,47 @@ impl Scene {
             }
 
             // TODO: Generalize?
+            // Meshes, images and kd-trees don't reference other resources, so they're
+            // initialized apart from the scene and shared with other scenes.
             for (resource_type, uninit_ref, init_ref) in pending_processing {
-                let file_data = file_store.fetch_file(&uninit_ref).await;
+                let context = || format!("Failed to load {} {}", resource_type, uninit_ref);
 
                 match resource_type {
                     ResourceType::Mesh => {
-                        let mesh = MeshUninit::load(&file_data).init(&mut references);
+                        let load = |data: &[u8]| {
+                            let mesh = MeshUninit::load(data)?;
+                            Ok(mesh.init(&mut ReferenceMapping::default()))
+                        };
+                        let mesh = files
+                            .load_shared(&uninit_ref, resource_type, load)
+                            .await
+                            .with_context(context)?;
                         loaded_meshes.insert(init_ref, mesh);
                     }
                     ResourceType::Material => {
-                        let material = BoxedMaterial::load(&file_data).init(&mut references);
+                        let file_data = files.fetch_file(&uninit_ref, Some(resource_type)).await?;
+                        let material = BoxedMaterial::load(&file_data).with_context(context)?;
+                        let material = material.init(&mut references);
                         loaded_materials.insert(init_ref, material);
                     }
                     ResourceType::Image => {
-                        let image = Image::load(&file_data);
+                        let image = files
+                            .load_shared(&uninit_ref, resource_type, Image::load)
+                            .await
+                            .with_context(context)?;
                         loaded_images.insert(init_ref, image);
                     }
                     ResourceType::KdTree => {
-                        todo!()
+                        let load = |data: &[u8]| {
+                            let kd_tree = KdTree::load(data)?;
+                            Ok(kd_tree.init(&mut ReferenceMapping::default()))
+                        };
+                        let kd_tree = files
+                            .load_shared(&uninit_ref, resource_type, load)
+                            .await
+                            .with_context(context)?;
+                        loaded_kd_trees.insert(init_ref, kd_tree);
+                        kd_tree_paths.insert(init_ref, uninit_ref);
                     }
                 }
             }
@@ -107,7 +283,30 @@ impl Scene {
         for id in 0..loaded_images.len() {
             scene.images.push(loaded_images.remove(&id).unwrap());
         }
+        for id in 0..loaded_kd_trees.len() {
+            scene.kd_trees.push(loaded_kd_trees.remove(&id).unwrap());
+        }
 
-        scene
+        // Hierarchy is taken out of the scene so it can be prepared using the loaded resources.
+        let mut hierarchy =
+            std::mem::replace(&mut scene.hierarchy, Box::new(NodeCollection::default()));
+        hierarchy
+            .prepare(&mut scene)
+            .with_context(|| format!("Failed to prepare scene {}", scene_path))?;
+        scene.hierarchy = hierarchy;
+        scene.lights.build_distribution();
+
+        Ok((scene, kd_tree_paths))
+    }
+
+    // Probability of sampling the environment instead of area lights for direct lighting.
+    pub fn environment_light_probability(&self) -> f32 {
+        if self.environment.is_black() {
+            0.0
+        } else if !self.lights.has_samplable_area() {
+            1.0
+        } else {
+            0.5
+        }
     }
 }
@@ -1,21 +1,20 @@
 use std::collections::HashSet;
 
-use image::RgbaImage;
+use anyhow::Context;
+use image::Rgb32FImage;
 use math::{Vec2, Vec3};
 
 use super::{ReferenceReplacer, Resource, ResourceReferenceUninit};
 
-pub struct Image(RgbaImage);
+// Stored as floats so HDR images (.hdr, .exr) keep their range.
+pub struct Image(Rgb32FImage);
 
 impl Resource for Image {
     type Initialized = Image;
 
-    fn load(data: &[u8]) -> Self {
-        Image(
-            image::load_from_memory(data)
-                .expect("Incorrect image format")
-                .to_rgba8(),
-        )
+    fn load(data: &[u8]) -> anyhow::Result<Self> {
+        let image = image::load_from_memory(data).context("Incorrect image format")?;
+        Ok(Image(image.to_rgb32f()))
     }
 
     fn collect_references(&self) -> HashSet<ResourceReferenceUninit> {
@@ -37,11 +36,6 @@ impl Image {
     }
 
     pub fn get_pixel(&self, coords: Vec2) -> Vec3 {
-        let pixel = self.0.get_pixel(coords.x as u32, coords.y as u32);
-        Vec3::new(
-            pixel.0[0] as f32 / 256.0,
-            pixel.0[1] as f32 / 256.0,
-            pixel.0[2] as f32 / 256.0,
-        )
+        Vec3::from(self.0.get_pixel(coords.x as u32, coords.y as u32).0)
     }
 }
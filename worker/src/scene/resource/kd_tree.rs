@@ -0,0 +1,500 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use math::Vec3;
+
+use super::{ReferenceReplacer, Resource, ResourceReferenceUninit};
+use crate::{aabb::Aabb, ray::Ray, renderer::cpu_renderer::RayTraceResult};
+
+const FILE_MAGIC: [u8; 4] = *b"KDTR";
+const FILE_VERSION: u32 = 1;
+
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 80.0;
+const EMPTY_BONUS: f32 = 0.5;
+const MAX_LEAF_PRIMITIVES: usize = 4;
+const MAX_BAD_REFINES: usize = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+enum KdNode {
+    // Below child always directly follows its parent.
+    Interior {
+        axis: u8,
+        split: f32,
+        above_child: u32,
+    },
+    Leaf {
+        first: u32,
+        count: u32,
+    },
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct KdTree {
+    primitive_bounds_md5: [u8; 16],
+    bounds: Aabb,
+    nodes: Vec<KdNode>,
+    primitive_ids: Vec<u32>,
+    // Primitives with infinite bounds (e.g. planes) are tested against every ray.
+    unbounded_ids: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KdTreeFileHeader {
+    magic: [u8; 4],
+    version: u32,
+}
+
+impl Resource for KdTree {
+    type Initialized = KdTree;
+
+    // Empty or outdated files produce an empty tree which gets rebuilt when the scene is prepared.
+    fn load(data: &[u8]) -> anyhow::Result<Self> {
+        if data.is_empty() {
+            return Ok(KdTree::default());
+        }
+
+        let (header, data): (KdTreeFileHeader, _) =
+            postcard::take_from_bytes(data).context("Incorrect kd-tree file format")?;
+        if header.magic != FILE_MAGIC {
+            anyhow::bail!("Incorrect kd-tree file format");
+        }
+        if header.version != FILE_VERSION {
+            println!(
+                "Kd-tree file version {} is outdated, it will be rebuilt",
+                header.version
+            );
+            return Ok(KdTree::default());
+        }
+
+        postcard::from_bytes(data).context("Incorrect kd-tree file format")
+    }
+
+    fn collect_references(&self) -> HashSet<ResourceReferenceUninit> {
+        HashSet::new()
+    }
+
+    fn init(self, _: &mut dyn ReferenceReplacer) -> Self::Initialized {
+        self
+    }
+}
+
+impl KdTree {
+    pub fn build(primitive_bounds: &[Aabb]) -> KdTree {
+        let mut tree = KdTree {
+            primitive_bounds_md5: Self::primitive_bounds_md5(primitive_bounds),
+            ..Default::default()
+        };
+
+        let mut bounded_ids = vec![];
+        for (id, bounds) in primitive_bounds.iter().enumerate() {
+            if bounds.is_empty() {
+                continue;
+            }
+            if bounds.is_finite() {
+                tree.bounds = tree.bounds.union(bounds);
+                bounded_ids.push(id as u32);
+            } else {
+                tree.unbounded_ids.push(id as u32);
+            }
+        }
+
+        if bounded_ids.is_empty() {
+            return tree;
+        }
+
+        let max_depth = (8.0 + 1.3 * (bounded_ids.len() as f32).log2()).round() as usize;
+        let mut builder = KdTreeBuilder {
+            primitive_bounds,
+            nodes: vec![],
+            primitive_ids: vec![],
+        };
+        builder.build_node(tree.bounds, bounded_ids, max_depth, 0);
+
+        tree.nodes = builder.nodes;
+        tree.primitive_ids = builder.primitive_ids;
+        tree
+    }
+
+    fn primitive_bounds_md5(primitive_bounds: &[Aabb]) -> [u8; 16] {
+        let data: Vec<_> = primitive_bounds
+            .iter()
+            .flat_map(|bounds| [bounds.min, bounds.max])
+            .flat_map(|v| [v.x, v.y, v.z])
+            .flat_map(f32::to_le_bytes)
+            .collect();
+        md5::compute(data).0
+    }
+
+    // Tree is considered outdated if it was built over different primitives.
+    pub fn is_built_for(&self, primitive_bounds: &[Aabb]) -> bool {
+        self.primitive_bounds_md5 == Self::primitive_bounds_md5(primitive_bounds)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let header = KdTreeFileHeader {
+            magic: FILE_MAGIC,
+            version: FILE_VERSION,
+        };
+        let mut data = postcard::to_stdvec(&header).unwrap();
+        data.extend(postcard::to_stdvec(self).unwrap());
+        data
+    }
+
+    pub fn trace_ray(
+        &self,
+        ray: &Ray,
+        mut trace_primitive: impl FnMut(usize, &Ray) -> RayTraceResult,
+    ) -> RayTraceResult {
+        let mut result = RayTraceResult::void();
+        let mut ray = Ray::new(ray.source, ray.direction, ray.min, ray.max);
+
+        let mut test_primitive = |id: u32, ray: &mut Ray, result: &mut RayTraceResult| {
+            let primitive_result = trace_primitive(id as usize, ray);
+            if primitive_result.hit && primitive_result.t <= ray.max {
+                ray.max = primitive_result.t;
+                *result = primitive_result;
+            }
+        };
+
+        for &id in &self.unbounded_ids {
+            test_primitive(id, &mut ray, &mut result);
+        }
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let Some((mut t_min, mut t_max)) = self.bounds.intersect(&ray) else {
+            return result;
+        };
+
+        let inv_direction = Vec3::new(
+            1.0 / ray.direction.x,
+            1.0 / ray.direction.y,
+            1.0 / ray.direction.z,
+        );
+
+        let mut stack = vec![];
+        let mut node_id = 0;
+        loop {
+            // Nodes are visited front to back so nothing closer can be found further.
+            if result.hit && ray.max < t_min {
+                break;
+            }
+
+            match self.nodes[node_id] {
+                KdNode::Interior {
+                    axis,
+                    split,
+                    above_child,
+                } => {
+                    let axis = axis as usize;
+                    let t_plane = (split - ray.source[axis]) * inv_direction[axis];
+
+                    let below_first = ray.source[axis] < split
+                        || (ray.source[axis] == split && ray.direction[axis] <= 0.0);
+                    let (first, second) = if below_first {
+                        (node_id + 1, above_child as usize)
+                    } else {
+                        (above_child as usize, node_id + 1)
+                    };
+
+                    if t_plane > t_max || t_plane <= 0.0 {
+                        node_id = first;
+                    } else if t_plane < t_min {
+                        node_id = second;
+                    } else {
+                        stack.push((second, t_plane, t_max));
+                        node_id = first;
+                        t_max = t_plane;
+                    }
+                }
+                KdNode::Leaf { first, count } => {
+                    let first = first as usize;
+                    for &id in &self.primitive_ids[first..first + count as usize] {
+                        test_primitive(id, &mut ray, &mut result);
+                    }
+
+                    match stack.pop() {
+                        Some((next_node_id, next_t_min, next_t_max)) => {
+                            node_id = next_node_id;
+                            t_min = next_t_min;
+                            t_max = next_t_max;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BoundEdge {
+    t: f32,
+    primitive_id: u32,
+    is_start: bool,
+}
+
+struct KdTreeBuilder<'a> {
+    primitive_bounds: &'a [Aabb],
+    nodes: Vec<KdNode>,
+    primitive_ids: Vec<u32>,
+}
+
+impl KdTreeBuilder<'_> {
+    fn make_leaf(&mut self, ids: Vec<u32>) {
+        self.nodes.push(KdNode::Leaf {
+            first: self.primitive_ids.len() as u32,
+            count: ids.len() as u32,
+        });
+        self.primitive_ids.extend(ids);
+    }
+
+    fn collect_edges(&self, ids: &[u32], axis: usize) -> Vec<BoundEdge> {
+        let mut edges: Vec<_> = ids
+            .iter()
+            .flat_map(|&primitive_id| {
+                let bounds = &self.primitive_bounds[primitive_id as usize];
+                [
+                    BoundEdge {
+                        t: bounds.min[axis],
+                        primitive_id,
+                        is_start: true,
+                    },
+                    BoundEdge {
+                        t: bounds.max[axis],
+                        primitive_id,
+                        is_start: false,
+                    },
+                ]
+            })
+            .collect();
+        // Start edges go first when positions are equal.
+        edges.sort_by(|a, b| a.t.total_cmp(&b.t).then(b.is_start.cmp(&a.is_start)));
+        edges
+    }
+
+    // Returns the cost and the edge id of the cheapest split along the axis.
+    fn find_split(&self, bounds: &Aabb, edges: &[BoundEdge], axis: usize) -> Option<(f32, usize)> {
+        let diagonal = bounds.diagonal();
+        let inv_area = 1.0 / bounds.surface_area();
+        let other_axis_0 = (axis + 1) % 3;
+        let other_axis_1 = (axis + 2) % 3;
+        let cap_area = diagonal[other_axis_0] * diagonal[other_axis_1];
+        let side_length = diagonal[other_axis_0] + diagonal[other_axis_1];
+
+        let mut best: Option<(f32, usize)> = None;
+        let mut below_count = 0;
+        let mut above_count = edges.len() / 2;
+        for (edge_id, edge) in edges.iter().enumerate() {
+            if !edge.is_start {
+                above_count -= 1;
+            }
+
+            if edge.t > bounds.min[axis] && edge.t < bounds.max[axis] {
+                let below_area = 2.0 * (cap_area + (edge.t - bounds.min[axis]) * side_length);
+                let above_area = 2.0 * (cap_area + (bounds.max[axis] - edge.t) * side_length);
+                let bonus = if below_count == 0 || above_count == 0 {
+                    EMPTY_BONUS
+                } else {
+                    0.0
+                };
+                let cost = TRAVERSAL_COST
+                    + INTERSECTION_COST
+                        * (1.0 - bonus)
+                        * (below_area * inv_area * below_count as f32
+                            + above_area * inv_area * above_count as f32);
+
+                if best.is_none_or(|(best_cost, _)| cost < best_cost) {
+                    best = Some((cost, edge_id));
+                }
+            }
+
+            if edge.is_start {
+                below_count += 1;
+            }
+        }
+
+        best
+    }
+
+    fn build_node(&mut self, bounds: Aabb, ids: Vec<u32>, depth: usize, mut bad_refines: usize) {
+        if ids.len() <= MAX_LEAF_PRIMITIVES || depth == 0 {
+            self.make_leaf(ids);
+            return;
+        }
+
+        let leaf_cost = INTERSECTION_COST * ids.len() as f32;
+
+        let mut best: Option<(f32, usize, usize, Vec<BoundEdge>)> = None;
+        let mut axis = bounds.max_extent_axis();
+        for _ in 0..3 {
+            let edges = self.collect_edges(&ids, axis);
+            if let Some((cost, edge_id)) = self.find_split(&bounds, &edges, axis) {
+                best = Some((cost, axis, edge_id, edges));
+                break;
+            }
+            axis = (axis + 1) % 3;
+        }
+
+        let Some((cost, axis, edge_id, edges)) = best else {
+            self.make_leaf(ids);
+            return;
+        };
+
+        if cost > leaf_cost {
+            bad_refines += 1;
+        }
+        if (cost > 4.0 * leaf_cost && ids.len() < 16) || bad_refines == MAX_BAD_REFINES {
+            self.make_leaf(ids);
+            return;
+        }
+
+        let below_ids: Vec<_> = edges[..edge_id]
+            .iter()
+            .filter(|edge| edge.is_start)
+            .map(|edge| edge.primitive_id)
+            .collect();
+        let above_ids: Vec<_> = edges[edge_id + 1..]
+            .iter()
+            .filter(|edge| !edge.is_start)
+            .map(|edge| edge.primitive_id)
+            .collect();
+        let split = edges[edge_id].t;
+
+        let mut below_bounds = bounds;
+        below_bounds.max[axis] = split;
+        let mut above_bounds = bounds;
+        above_bounds.min[axis] = split;
+
+        let node_id = self.nodes.len();
+        self.nodes.push(KdNode::Leaf { first: 0, count: 0 });
+
+        self.build_node(below_bounds, below_ids, depth - 1, bad_refines);
+        let above_child = self.nodes.len() as u32;
+        self.build_node(above_bounds, above_ids, depth - 1, bad_refines);
+
+        self.nodes[node_id] = KdNode::Interior {
+            axis: axis as u8,
+            split,
+            above_child,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    fn random_vec3(rng: &mut StdRng, min: f32, max: f32) -> Vec3 {
+        Vec3::new(
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+            rng.gen_range(min..max),
+        )
+    }
+
+    fn random_boxes(rng: &mut StdRng, count: usize) -> Vec<Aabb> {
+        (0..count)
+            .map(|_| {
+                let min = random_vec3(rng, -10.0, 10.0);
+                Aabb::new(min, min + random_vec3(rng, 0.0, 2.0))
+            })
+            .collect()
+    }
+
+    // Random rays along with the axis-parallel ones, starting both inside and outside of the boxes.
+    fn random_rays(rng: &mut StdRng) -> Vec<Ray> {
+        let mut directions: Vec<_> = (0..1000)
+            .map(|_| random_vec3(rng, -1.0, 1.0).normalized())
+            .collect();
+        for axis in 0..3 {
+            for sign in [-1.0, 1.0] {
+                let mut direction = Vec3::default();
+                direction[axis] = sign;
+                directions.extend([direction; 100]);
+            }
+        }
+        directions
+            .into_iter()
+            .map(|direction| Ray::new(random_vec3(rng, -15.0, 15.0), direction, 0.0, f32::MAX))
+            .collect()
+    }
+
+    fn trace_box(bounds: &Aabb, ray: &Ray) -> RayTraceResult {
+        match bounds.intersect(ray) {
+            Some((t, _)) => RayTraceResult {
+                hit: true,
+                t,
+                ..RayTraceResult::void()
+            },
+            None => RayTraceResult::void(),
+        }
+    }
+
+    fn assert_matches_brute_force(tree: &KdTree, boxes: &[Aabb], rays: &[Ray]) {
+        for ray in rays {
+            let result = tree.trace_ray(ray, |id, ray| trace_box(&boxes[id], ray));
+            let expected = boxes
+                .iter()
+                .filter_map(|bounds| bounds.intersect(ray))
+                .map(|(t, _)| t)
+                .min_by(f32::total_cmp);
+            assert_eq!(result.hit.then_some(result.t), expected);
+        }
+    }
+
+    #[test]
+    fn traversal_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let rays = random_rays(&mut rng);
+        for count in [1, 10, 1000] {
+            let boxes = random_boxes(&mut rng, count);
+            assert_matches_brute_force(&KdTree::build(&boxes), &boxes, &rays);
+        }
+    }
+
+    #[test]
+    fn traversal_of_overlapping_boxes_matches_brute_force() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let rays = random_rays(&mut rng);
+        let mut boxes = vec![Aabb::new(Vec3::new_xyz(-1.0), Vec3::new_xyz(1.0)); 100];
+        boxes.extend(random_boxes(&mut rng, 100));
+        boxes.push(Aabb::empty());
+        assert_matches_brute_force(&KdTree::build(&boxes), &boxes, &rays);
+    }
+
+    #[test]
+    fn loaded_tree_is_the_same() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let boxes = random_boxes(&mut rng, 500);
+        let tree = KdTree::build(&boxes);
+
+        let loaded = KdTree::load(&tree.to_bytes()).unwrap();
+        assert_eq!(loaded.to_bytes(), tree.to_bytes());
+        assert!(loaded.is_built_for(&boxes));
+        assert!(!loaded.is_built_for(&boxes[1..]));
+        assert_matches_brute_force(&loaded, &boxes, &random_rays(&mut rng));
+    }
+
+    #[test]
+    fn empty_and_outdated_files_are_rebuilt() {
+        let boxes = random_boxes(&mut StdRng::seed_from_u64(3), 10);
+        assert!(!KdTree::load(&[]).unwrap().is_built_for(&boxes));
+
+        let mut data = KdTree::build(&boxes).to_bytes();
+        data[4] = FILE_VERSION as u8 + 1;
+        assert!(!KdTree::load(&data).unwrap().is_built_for(&boxes));
+
+        data[0] = 0;
+        assert!(KdTree::load(&data).is_err());
+    }
+}
@@ -1,20 +1,20 @@
 use std::collections::HashSet;
 use std::sync::Arc;
 
-use rand::*;
 use serde::{Deserialize, Serialize};
 
 use math::Vec3;
 
 use super::{
-    material_input::{MaterialInput, MaterialInputUninit},
     Material, MaterialUninit, RayTraceResult,
+    material_input::{MaterialInput, MaterialInputUninit},
 };
 use crate::{
-    renderer::cpu_renderer::{self, GetColorResult},
+    renderer::cpu_renderer::{self, BsdfSample},
+    sampler::Sampler,
     scene::{
-        resource::{ReferenceReplacer, ResourceReferenceUninit},
         Scene,
+        resource::{ReferenceReplacer, ResourceReferenceUninit},
     },
 };
 
@@ -61,6 +61,10 @@ impl BaseMaterial {
 
         0.5 * a * a * (1.0 + b * b)
     }
+
+    fn diffuse_probability(&self) -> f32 {
+        (1.0 - self.reflective - self.emissive - self.refractive).max(0.0)
+    }
 }
 
 #[typetag::serde(name = "base")]
@@ -85,25 +89,35 @@ impl MaterialUninit for BaseMaterialUninit {
 impl Material for BaseMaterial {}
 
 impl cpu_renderer::Material for BaseMaterial {
-    fn get_color(
+    fn sample(
         &self,
-        dir: Vec3,
+        wo: Vec3,
         trace_result: &RayTraceResult,
         scene: Arc<Scene>,
-    ) -> GetColorResult {
-        let random_num = thread_rng().gen_range(0.0..1.0);
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        let dir = wo * -1.0;
+        let delta_sample = |wi| {
+            Some(BsdfSample {
+                wi,
+                weight: Vec3::new_xyz(1.0),
+                pdf: 0.0,
+                is_delta: true,
+            })
+        };
+
+        // Every lobe consumes the same dimensions, so they stay aligned between samples.
+        let random_num = sampler.get_1d();
+        let rand = sampler.get_2d();
         if random_num < self.reflective {
             // reflect
-            GetColorResult::NextRayColorMultiplierAndDirection(
-                Vec3::new_xyz(1.0),
-                dir.reflect(trace_result.normal),
-            )
+            delta_sample(dir.reflect(trace_result.normal))
         } else if random_num < self.reflective + self.emissive {
-            // emit
-            GetColorResult::Color(self.emission)
+            // emit, emission itself is returned by emitted()
+            None
         } else if random_num < self.reflective + self.emissive + self.refractive {
             // refract
-            let cos = (Vec3::default() - dir).dot(trace_result.normal);
+            let cos = wo.dot(trace_result.normal);
             let refraction = if trace_result.hit_inside {
                 self.refraction
             } else {
@@ -111,8 +125,7 @@ impl cpu_renderer::Material for BaseMaterial {
             };
             let fresnel = Self::fresnel_reflection(cos, refraction);
 
-            let rand = thread_rng().gen_range(0.0..1.0);
-            let new_dir = if rand < fresnel {
+            let wi = if rand.x < fresnel {
                 // reflect
                 dir.reflect(trace_result.normal)
             } else {
@@ -126,20 +139,96 @@ impl cpu_renderer::Material for BaseMaterial {
                 }
             };
 
-            GetColorResult::NextRayColorMultiplierAndDirection(Vec3::new_xyz(1.0), new_dir)
+            delta_sample(wi)
         } else {
             // diffuse
-            let mut new_direction = Vec3::random_on_unit_sphere(
-                thread_rng().gen_range(0.0..1.0),
-                thread_rng().gen_range(0.0..1.0),
-            );
+            let wi =
+                Vec3::cosine_weighted_random_on_hemisphere(rand.x, rand.y, trace_result.normal);
+
+            Some(BsdfSample {
+                wi,
+                weight: self.color.sample(scene, trace_result.uv),
+                pdf: self.pdf(wi, wo, trace_result),
+                is_delta: false,
+            })
+        }
+    }
+
+    fn eval(&self, wi: Vec3, _: Vec3, trace_result: &RayTraceResult, scene: Arc<Scene>) -> Vec3 {
+        let cos = wi.dot(trace_result.normal);
+        if cos <= 0.0 {
+            return Vec3::default();
+        }
+        let color = self.color.sample(scene, trace_result.uv);
+        color * (self.diffuse_probability() * cos * math::INV_PI)
+    }
 
-            if new_direction.dot(trace_result.normal) < 0.0 {
-                new_direction = Vec3::default() - new_direction;
-            }
+    fn pdf(&self, wi: Vec3, _: Vec3, trace_result: &RayTraceResult) -> f32 {
+        let cos = wi.dot(trace_result.normal).max(0.0);
+        self.diffuse_probability() * cos * math::INV_PI
+    }
 
-            let color = self.color.sample(scene, trace_result.uv);
-            GetColorResult::NextRayColorMultiplierAndDirection(color, new_direction)
+    fn is_emissive(&self) -> bool {
+        self.emissive > 0.0
+    }
+
+    fn emitted(&self, _: Vec3, _: &RayTraceResult) -> Vec3 {
+        self.emission * self.emissive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use math::Vec2;
+
+    use super::*;
+    use crate::scene::{hierarchy::node_collection::NodeCollection, resource::ReferenceMapping};
+
+    // Returns the same value for every dimension and counts them.
+    struct CountingSampler {
+        value: f32,
+        dimensions: usize,
+    }
+
+    impl Sampler for CountingSampler {
+        fn get_1d(&mut self) -> f32 {
+            self.dimensions += 1;
+            self.value
+        }
+
+        fn get_2d(&mut self) -> Vec2 {
+            self.dimensions += 2;
+            Vec2::new(self.value, self.value)
         }
     }
+
+    #[test]
+    fn every_lobe_consumes_same_dimensions() {
+        let material: BaseMaterialUninit = serde_json::from_str(
+            r#"{ "reflective": 0.25, "emissive": 0.25, "refractive": 0.25, "refraction": 1.5 }"#,
+        )
+        .unwrap();
+        let material = Box::new(material).init(&mut ReferenceMapping::default());
+        let scene = Arc::new(Scene::new(Box::new(NodeCollection::default())));
+        let trace_result = RayTraceResult {
+            hit: true,
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            ..RayTraceResult::void()
+        };
+
+        // Reflective, emissive, refractive and diffuse lobes are picked in turn.
+        let dimensions: Vec<_> = [0.1, 0.3, 0.6, 0.9]
+            .into_iter()
+            .map(|value| {
+                let mut sampler = CountingSampler {
+                    value,
+                    dimensions: 0,
+                };
+                let wo = Vec3::new(0.0, 0.0, 1.0);
+                material.sample(wo, &trace_result, scene.clone(), &mut sampler);
+                sampler.dimensions
+            })
+            .collect();
+        assert_eq!(dimensions, [3; 4]);
+    }
 }
@@ -1,5 +1,7 @@
 use std::collections::HashSet;
 
+use anyhow::Context;
+
 pub mod base;
 pub mod material_input;
 pub mod pbr;
@@ -22,12 +24,13 @@ pub struct BoxedMaterial(Box<dyn MaterialUninit>);
 impl Resource for BoxedMaterial {
     type Initialized = Box<dyn Material>;
 
-    fn load(data: &[u8]) -> Self
+    fn load(data: &[u8]) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        let data = String::from_utf8(data.to_vec()).unwrap();
-        BoxedMaterial(serde_json::de::from_str(&data).unwrap())
+        let data = std::str::from_utf8(data).context("Material file isn't valid UTF-8")?;
+        let material = serde_json::de::from_str(data).context("Incorrect material format")?;
+        Ok(BoxedMaterial(material))
     }
 
     fn init(self, reference_replacer: &mut dyn ReferenceReplacer) -> Box<dyn Material> {
@@ -1,19 +1,22 @@
 use std::{collections::HashSet, sync::Arc};
 
-use rand::*;
 use serde::{Deserialize, Serialize};
 
 use math::Vec3;
 
 use crate::{
-    renderer::cpu_renderer::{self, GetColorResult},
-    scene::resource::{ReferenceReplacer, ResourceReferenceUninit},
+    renderer::cpu_renderer::{self, BsdfSample},
+    sampler::Sampler,
     scene::Scene,
+    scene::resource::{ReferenceReplacer, ResourceReferenceUninit},
 };
 
 use super::RayTraceResult;
 use super::{Material, MaterialUninit};
 
+// Too narrow microfacet distributions break sampling.
+const MIN_SAMPLING_ALPHA: f32 = 0.001;
+
 // @TODO: Parametrize all fields with MaterialInput
 #[derive(Deserialize, Serialize)]
 #[serde(default)]
@@ -52,6 +55,13 @@ impl PBRMaterial {
         roughness_sqr_sqr / (denom_sqrt * denom_sqrt * math::PI)
     }
 
+    // Unclamped ndf that is importance sampled.
+    fn sampling_ndf(&self, nh: f32) -> f32 {
+        let alpha_sqr = self.roughness_sqr.max(MIN_SAMPLING_ALPHA).powi(2);
+        let denom_sqrt = nh * nh * (alpha_sqr - 1.0) + 1.0;
+        alpha_sqr / (denom_sqrt * denom_sqrt * math::PI)
+    }
+
     fn geometry(&self, angle_cos: f32) -> f32 {
         let k = (1.0 + self.roughness).powi(2) / 8.0;
         angle_cos / (angle_cos * (1.0 - k) + k)
@@ -72,6 +82,19 @@ impl PBRMaterial {
         diffuse_k * diffuse
     }
 
+    // Multiplied by cosine of wi.
+    fn brdf(&self, wi: Vec3, wo: Vec3, normal: Vec3) -> Vec3 {
+        let ni = normal.dot(wi);
+        let no = normal.dot(wo);
+        if ni <= 0.0 || no <= 0.0 {
+            return Vec3::default();
+        }
+
+        let diffuse = self.brdf_diffuse(wo, wi);
+        let specular = self.brdf_specular(normal, wo, wi);
+        (diffuse + specular) * ni
+    }
+
     fn brdf_specular(&self, normal: Vec3, input_dir: Vec3, output_dir: Vec3) -> Vec3 {
         let ni = normal.dot(input_dir);
         let no = normal.dot(output_dir);
@@ -103,34 +126,58 @@ impl MaterialUninit for PBRMaterial {
 impl Material for PBRMaterial {}
 
 impl cpu_renderer::Material for PBRMaterial {
-    fn get_color(&self, dir: Vec3, trace_result: &RayTraceResult, _: Arc<Scene>) -> GetColorResult {
-        let input_dir = dir * -1.0;
-
-        let rand_0 = thread_rng().gen_range(0.0..1.0);
-        let rand_1 = thread_rng().gen_range(0.0..1.0);
-
-        let (mul, output_dir, selection_probability) = if thread_rng().gen_bool(0.5) {
+    fn sample(
+        &self,
+        wo: Vec3,
+        trace_result: &RayTraceResult,
+        _: Arc<Scene>,
+        sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        let lobe = sampler.get_1d();
+        let rand = sampler.get_2d();
+        let (rand_0, rand_1) = (rand.x, rand.y);
+
+        let wi = if lobe < 0.5 {
             // Diffuse
-            let output_dir =
-                Vec3::cosine_weighted_random_on_hemisphere(rand_0, rand_1, trace_result.normal);
-            let output_dir_pdf = output_dir.dot(trace_result.normal);
-
-            let diffuse = self.brdf_diffuse(input_dir, output_dir);
-
-            (diffuse, output_dir, output_dir_pdf)
+            Vec3::cosine_weighted_random_on_hemisphere(rand_0, rand_1, trace_result.normal)
         } else {
             // Specular
-            let output_dir = Vec3::random_on_hemisphere(rand_0, rand_1, trace_result.normal);
-            let output_dir_pdf = 1.0;
+            let alpha = self.roughness_sqr.max(MIN_SAMPLING_ALPHA);
+            let h = Vec3::ggx_random_on_hemisphere(rand_0, rand_1, trace_result.normal, alpha);
+            (wo * -1.0).reflect(h)
+        };
 
-            let specular = self.brdf_specular(trace_result.normal, input_dir, output_dir);
+        let pdf = self.pdf(wi, wo, trace_result);
+        if pdf <= 0.0 {
+            return None;
+        }
 
-            (specular, output_dir, output_dir_pdf)
-        };
+        Some(BsdfSample {
+            wi,
+            weight: self.brdf(wi, wo, trace_result.normal) / pdf,
+            pdf,
+            is_delta: false,
+        })
+    }
+
+    fn eval(&self, wi: Vec3, wo: Vec3, trace_result: &RayTraceResult, _: Arc<Scene>) -> Vec3 {
+        self.brdf(wi, wo, trace_result.normal)
+    }
+
+    fn pdf(&self, wi: Vec3, wo: Vec3, trace_result: &RayTraceResult) -> f32 {
+        let ni = trace_result.normal.dot(wi);
+        if ni <= 0.0 {
+            return 0.0;
+        }
+
+        let h = (wo + wi).normalized();
+        let nh = trace_result.normal.dot(h).max(0.0);
+        let ho = h.dot(wo).abs().max(math::EPSILON);
 
-        let mul =
-            mul * (output_dir.dot(trace_result.normal) / selection_probability * math::PI * 1.0);
+        let diffuse_pdf = ni * math::INV_PI;
+        let specular_pdf = self.sampling_ndf(nh) * nh / (4.0 * ho);
 
-        GetColorResult::NextRayColorMultiplierAndDirection(mul, output_dir)
+        // Both lobes are selected with equal probability.
+        0.5 * (diffuse_pdf + specular_pdf)
     }
 }
@@ -2,15 +2,19 @@ use std::{collections::HashSet, sync::Arc};
 
 use serde::Deserialize;
 
+use math::Vec3;
+
 mod obj_loader;
 pub mod triangle;
 
 use crate::{
+    aabb::Aabb,
+    bvh::Bvh,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::{
-        resource::{ReferenceReplacer, Resource, ResourceReferenceUninit},
         Scene,
+        resource::{ReferenceReplacer, Resource, ResourceReferenceUninit},
     },
 };
 use triangle::{Triangle, TriangleUninit};
@@ -21,18 +25,21 @@ pub type Mesh = MeshGeneric<Triangle>;
 #[derive(Deserialize)]
 pub struct MeshGeneric<T> {
     triangles: Vec<T>,
+    #[serde(skip)]
+    bvh: Bvh,
 }
 
 impl Resource for MeshUninit {
     type Initialized = Mesh;
 
-    fn load(data: &[u8]) -> Self
+    fn load(data: &[u8]) -> anyhow::Result<Self>
     where
         Self: Sized,
     {
-        MeshUninit {
-            triangles: obj_loader::load(data),
-        }
+        Ok(MeshUninit {
+            triangles: obj_loader::load(data)?,
+            bvh: Bvh::default(),
+        })
     }
 
     fn collect_references(&self) -> HashSet<ResourceReferenceUninit> {
@@ -40,26 +47,45 @@ impl Resource for MeshUninit {
     }
 
     fn init(self, _: &mut dyn ReferenceReplacer) -> Self::Initialized {
+        let triangles: Vec<_> = self
+            .triangles
+            .into_iter()
+            .map(TriangleUninit::init)
+            .collect();
+        let triangle_bounds: Vec<_> = triangles.iter().map(Triangle::bounds).collect();
+
         Mesh {
-            triangles: self
-                .triangles
-                .into_iter()
-                .map(TriangleUninit::init)
-                .collect(),
+            bvh: Bvh::build(&triangle_bounds),
+            triangles,
         }
     }
 }
 
+impl Mesh {
+    pub fn triangle_vertices(&self) -> Vec<[Vec3; 3]> {
+        self.triangles.iter().map(Triangle::vertices).collect()
+    }
+}
+
 impl cpu_renderer::SceneNode for Mesh {
-    fn trace_ray(&self, scene: Arc<Scene>, ray: &Ray) -> RayTraceResult {
-        let mut result = RayTraceResult::void();
-        result.t = f32::INFINITY;
-        for triangle in &self.triangles {
-            let triangle_result = triangle.trace_ray(scene.clone(), ray);
-            if triangle_result.hit && triangle_result.t < result.t {
-                result = triangle_result;
-            }
-        }
-        result
+    fn trace_ray(&self, _: Arc<Scene>, ray: &Ray) -> RayTraceResult {
+        self.bvh
+            .trace_ray(ray, |id, ray| self.triangles[id].intersect(ray))
+    }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    fn primitive_count(&self, _: &Scene) -> usize {
+        self.triangles.len()
+    }
+
+    fn primitive_bounds(&self, _: &Scene, id: usize) -> Aabb {
+        self.triangles[id].bounds()
+    }
+
+    fn trace_primitive(&self, _: Arc<Scene>, ray: &Ray, id: usize) -> RayTraceResult {
+        self.triangles[id].intersect(ray)
     }
 }
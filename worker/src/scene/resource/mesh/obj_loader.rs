@@ -1,12 +1,13 @@
 use std::{convert::TryInto, io::BufReader};
 
+use anyhow::Context;
 use tobj::{LoadError, MTLLoadResult};
 
 use math::{Vec2, Vec3};
 
 use super::triangle::TriangleUninit;
 
-pub fn load(file_data: &[u8]) -> Vec<TriangleUninit> {
+pub fn load(file_data: &[u8]) -> anyhow::Result<Vec<TriangleUninit>> {
     // @NOTE: tobj loads uvs as [f32, f32] even if there's third texture coord.
     let mut reader = BufReader::new(file_data);
     let (models, _) = tobj::load_obj_buf(
@@ -17,7 +18,7 @@ pub fn load(file_data: &[u8]) -> Vec<TriangleUninit> {
         },
         |_| MTLLoadResult::Err(LoadError::GenericFailure),
     )
-    .unwrap();
+    .context("Incorrect obj format")?;
 
     let mut triangles = vec![];
     for model in models {
@@ -78,5 +79,5 @@ pub fn load(file_data: &[u8]) -> Vec<TriangleUninit> {
             }
         }
     }
-    triangles
+    Ok(triangles)
 }
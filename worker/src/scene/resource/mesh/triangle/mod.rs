@@ -7,6 +7,7 @@ use math::{Mat4, Vec2, Vec3, Vec4};
 mod vertex;
 
 use crate::{
+    aabb::Aabb,
     ray::Ray,
     renderer::cpu_renderer::{self, RayTraceResult},
     scene::Scene,
@@ -124,11 +125,16 @@ impl Triangle {
             + self.vertices[1].normal * coords.0[1]
             + self.vertices[2].normal * coords.0[2]
     }
-}
 
-// FIXME: Store material_id inside triangle?
-impl cpu_renderer::SceneNode for Triangle {
-    fn trace_ray(&self, _: Arc<Scene>, ray: &Ray) -> RayTraceResult {
+    pub fn vertices(&self) -> [Vec3; 3] {
+        self.vertices.each_ref().map(|vert| vert.position)
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        Aabb::from_points(&self.vertices())
+    }
+
+    pub fn intersect(&self, ray: &Ray) -> RayTraceResult {
         let mut result = RayTraceResult::void();
         // Moller-Trumbore algorithm
         let edge0 = self.vertices[1].position - self.vertices[0].position;
@@ -162,7 +168,23 @@ impl cpu_renderer::SceneNode for Triangle {
         let barycentric_coords = self.get_barycentric_coords(result.point);
         result.uv = self.get_uv(barycentric_coords);
         result.normal = self.get_normal(barycentric_coords);
+        // Normal should face the ray, hitting the back side means the ray is inside the mesh.
+        if result.normal.dot(ray.direction) > 0.0 {
+            result.normal = result.normal * -1.0;
+            result.hit_inside = true;
+        }
 
         result
     }
 }
+
+// FIXME: Store material_id inside triangle?
+impl cpu_renderer::SceneNode for Triangle {
+    fn trace_ray(&self, _: Arc<Scene>, ray: &Ray) -> RayTraceResult {
+        self.intersect(ray)
+    }
+
+    fn bounds(&self, _: &Scene) -> Aabb {
+        self.bounds()
+    }
+}
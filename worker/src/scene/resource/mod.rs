@@ -1,16 +1,18 @@
 use std::{
     collections::{HashMap, HashSet},
+    fmt::Display,
     iter,
 };
 
 pub mod image;
+pub mod kd_tree;
 pub mod material;
 pub mod mesh;
 
 pub trait Resource {
     type Initialized;
 
-    fn load(data: &[u8]) -> Self
+    fn load(data: &[u8]) -> anyhow::Result<Self>
     where
         Self: Sized;
     fn collect_references(&self) -> HashSet<ResourceReferenceUninit>;
@@ -120,6 +122,18 @@ pub enum ResourceType {
     Image,
 }
 
+impl Display for ResourceType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ResourceType::Mesh => "mesh",
+            ResourceType::Material => "material",
+            ResourceType::KdTree => "kd-tree",
+            ResourceType::Image => "image",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 impl ResourceType {
     pub fn get_all_variants() -> Vec<ResourceType> {
         vec![Self::Mesh, Self::Material, Self::KdTree, Self::Image]